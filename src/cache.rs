@@ -0,0 +1,605 @@
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sled::{Db, Tree};
+use tracing::{debug, trace, warn};
+
+use crate::{cap::Cap, config::Route, feed::Item, output::Out};
+
+/// How many times an item that never made it to delivered is retried before giving up on it.
+const MAX_RETRIES: u32 = 10;
+
+/// How long after an item was first seen it's retried for, if it never made it to delivered.
+const MAX_RETRY_AGE_HOURS: i64 = 48;
+
+/// The sled database and the trees capchat keeps in it.
+#[derive(Clone, Debug)]
+pub struct Cache {
+	db: Db,
+
+	/// Feed item GUIDs that have been seen, to a [`SeenItem`] tracking how far along they got.
+	///
+	/// Older databases have the bare CAP link as value; those items are considered delivered.
+	pub seen: Tree,
+
+	/// `feed\0guid` keys of items that haven't been delivered yet and are still worth retrying, to their CAP link.
+	pub stuck: Tree,
+
+	/// Feed item GUIDs whose CAP failed to fetch or parse, to a [`FailedItem`].
+	pub failed: Tree,
+
+	/// `route/guid` keys of CAPs that have been posted, to the identifier of the original alert they follow.
+	pub posted: Tree,
+
+	/// `route/sink/guid` keys of CAPs that a sink has successfully sent, to when that was.
+	pub delivered: Tree,
+
+	/// `route/sink/batch` keys to the [`Posted`] messages for that batch.
+	pub messages: Tree,
+
+	/// `route/sink/original` keys to the `route/sink/batch` key holding that alert's messages.
+	pub message_index: Tree,
+
+	/// `route/sink/guids/part` keys to the [`SentPart`] for parts of a send that has yet to complete.
+	pub progress: Tree,
+}
+
+/// How far along a feed item has got.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemState {
+	/// Found in a feed, but its CAP hasn't been fetched yet.
+	Discovered,
+
+	/// The CAP has been fetched and parsed, but not yet sent everywhere.
+	Fetched,
+
+	/// The CAP has been dealt with by every route (sent, or filtered out).
+	Delivered,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SeenItem {
+	pub link: String,
+	pub feed: String,
+	pub state: ItemState,
+
+	#[serde(default)]
+	pub discovered_at: Option<DateTime<Utc>>,
+
+	/// How many times it's been retried since it was discovered.
+	#[serde(default)]
+	pub retries: u32,
+}
+
+/// A feed item whose CAP couldn't be fetched or parsed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FailedItem {
+	pub guid: String,
+	pub link: String,
+	pub feed: String,
+	pub error: String,
+
+	/// How many times in a row it's failed.
+	pub attempts: u32,
+	pub failed_at: DateTime<Utc>,
+}
+
+/// Chat messages a sink posted for a batch of alerts, kept so they can be edited later.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Posted {
+	pub alerts: Vec<PostedAlert>,
+	pub messages: Vec<PostedMessage>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PostedAlert {
+	/// Identifier of the original alert.
+	pub guid: String,
+
+	/// Alerts without an expiry stay fresh until cancelled.
+	#[serde(default)]
+	pub expires: Option<DateTime<Utc>>,
+
+	/// Whether the alert has since been cancelled, superseded, or has expired.
+	#[serde(default)]
+	pub stale: bool,
+}
+
+/// A part of a multi-part send that went through.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SentPart {
+	/// Hash of the part's text and image, so a part is only skipped if it'd be sent the same.
+	pub digest: String,
+
+	/// Identifier the service gave the message, if any.
+	pub id: String,
+}
+
+/// The parts of one send to a sink that have gone through so far.
+///
+/// Sinks that post several messages for one output (an image then text, or a long text split up)
+/// record each part as it's sent, so that a retry after a failure partway through picks up where
+/// it left off instead of posting the first parts again.
+#[derive(Clone, Debug)]
+pub struct Progress {
+	tree: Tree,
+	key: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PostedMessage {
+	pub id: String,
+
+	#[serde(default)]
+	pub content: String,
+
+	#[serde(default)]
+	pub image: bool,
+}
+
+impl Cache {
+	pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+		let path = path.as_ref();
+		debug!(?path, "opening sled database");
+		Self::from_db(sled::open(path)?)
+	}
+
+	#[cfg(test)]
+	pub fn temporary() -> Result<Self> {
+		Self::from_db(sled::Config::new().temporary(true).open()?)
+	}
+
+	fn from_db(db: Db) -> Result<Self> {
+		// db.drop_tree("cache")?; // DEV
+		Ok(Self {
+			seen: db.open_tree("cache")?,
+			stuck: db.open_tree("stuck")?,
+			failed: db.open_tree("failed")?,
+			posted: db.open_tree("posted")?,
+			delivered: db.open_tree("delivered")?,
+			messages: db.open_tree("messages")?,
+			message_index: db.open_tree("message_index")?,
+			progress: db.open_tree("progress")?,
+			db,
+		})
+	}
+
+	pub async fn flush(&self) -> Result<()> {
+		debug!("flushing sled database");
+		self.db.flush_async().await?;
+		Ok(())
+	}
+
+	/// Record a feed item as discovered, and return whether it should be fetched.
+	///
+	/// That's if it's new, or with `retry` if it never made it to delivered.
+	pub fn discover(&self, feed: &str, item: &Item, retry: bool) -> Result<bool> {
+		let new = SeenItem {
+			link: item.link.clone(),
+			feed: feed.into(),
+			state: ItemState::Discovered,
+			discovered_at: Some(Utc::now()),
+			retries: 0,
+		};
+
+		let existing = match self.seen.compare_and_swap(
+			item.guid.as_bytes(),
+			None::<Vec<u8>>,
+			Some(serde_json::to_vec(&new)?),
+		)? {
+			Ok(()) => {
+				self.stuck
+					.insert(stuck_key(feed, &item.guid), item.link.as_bytes())?;
+				return Ok(true);
+			}
+			Err(err) => err.current,
+		};
+
+		Ok(
+			match existing.and_then(|v| serde_json::from_slice::<SeenItem>(&v).ok()) {
+				Some(seen) if retry && seen.state != ItemState::Delivered => {
+					self.should_retry(&item.guid, seen)?
+				}
+				_ => false,
+			},
+		)
+	}
+
+	/// Items of a feed that never made it to delivered, and haven't been given up on.
+	///
+	/// Call [`Cache::retry`] before retrying one, to count the attempt.
+	pub fn stuck_items(&self, feed: &str) -> Result<Vec<Item>> {
+		let prefix = stuck_key(feed, "");
+		self.stuck
+			.scan_prefix(&prefix)
+			.map(|entry| {
+				let (key, link) = entry?;
+				Ok(Item {
+					guid: String::from_utf8(key[prefix.len()..].to_vec())?,
+					link: String::from_utf8(link.to_vec())?,
+				})
+			})
+			.collect()
+	}
+
+	/// Count a retry of an item that never made it to delivered, and return whether to go ahead.
+	///
+	/// Items are given up on after [`MAX_RETRIES`] retries, or [`MAX_RETRY_AGE_HOURS`] after they
+	/// were first seen, so an alert that can never be delivered isn't fetched on every poll forever.
+	pub fn retry(&self, item: &Item) -> Result<bool> {
+		match self.seen.get(item.guid.as_bytes())? {
+			Some(value) => match serde_json::from_slice::<SeenItem>(&value) {
+				Ok(seen) if seen.state != ItemState::Delivered => {
+					self.should_retry(&item.guid, seen)
+				}
+				_ => Ok(false),
+			},
+			None => Ok(false),
+		}
+	}
+
+	fn should_retry(&self, guid: &str, mut seen: SeenItem) -> Result<bool> {
+		let too_old = seen.discovered_at.is_some_and(|at| {
+			Utc::now().signed_duration_since(at) > Duration::hours(MAX_RETRY_AGE_HOURS)
+		});
+		if too_old || seen.retries >= MAX_RETRIES {
+			if self.stuck.remove(stuck_key(&seen.feed, guid))?.is_some() {
+				warn!(%guid, retries=%seen.retries, discovered=?seen.discovered_at, "giving up on item that was never delivered");
+			}
+			return Ok(false);
+		}
+
+		seen.retries += 1;
+		debug!(%guid, state=?seen.state, retries=%seen.retries, "retrying item that wasn't delivered");
+		self.seen
+			.insert(guid.as_bytes(), serde_json::to_vec(&seen)?)?;
+		Ok(true)
+	}
+
+	pub fn set_item_state(&self, guid: &str, state: ItemState) -> Result<()> {
+		let mut seen: SeenItem = match self.seen.get(guid.as_bytes())? {
+			Some(value) => match serde_json::from_slice(&value) {
+				Ok(seen) => seen,
+				Err(_) => return Ok(()),
+			},
+			None => return Ok(()),
+		};
+
+		trace!(%guid, ?state, "updating item state");
+		if state == ItemState::Delivered {
+			self.stuck.remove(stuck_key(&seen.feed, guid))?;
+		}
+
+		seen.state = state;
+		self.seen
+			.insert(guid.as_bytes(), serde_json::to_vec(&seen)?)?;
+		Ok(())
+	}
+
+	/// Record that an item's CAP failed to fetch or parse.
+	pub fn record_failure(&self, feed: &str, item: &Item, error: String) -> Result<FailedItem> {
+		let attempts = match self.failed.get(item.guid.as_bytes())? {
+			Some(value) => serde_json::from_slice::<FailedItem>(&value).map_or(0, |f| f.attempts),
+			None => 0,
+		};
+
+		let failed = FailedItem {
+			guid: item.guid.clone(),
+			link: item.link.clone(),
+			feed: feed.into(),
+			error,
+			attempts: attempts + 1,
+			failed_at: Utc::now(),
+		};
+
+		trace!(?failed, "recording failed item");
+		self.failed
+			.insert(item.guid.as_bytes(), serde_json::to_vec(&failed)?)?;
+		Ok(failed)
+	}
+
+	/// Forget an item's failure, once it's been fetched successfully.
+	pub fn clear_failure(&self, guid: &str) -> Result<()> {
+		if self.failed.remove(guid.as_bytes())?.is_some() {
+			debug!(%guid, "previously-failed item has now been fetched");
+		}
+
+		Ok(())
+	}
+
+	/// Find the original alerts that a CAP's references point to, if we've posted them.
+	pub fn originals(&self, route: &Route, cap: &Cap) -> Result<Vec<String>> {
+		let mut originals = Vec::new();
+		for reference in &cap.references {
+			let key = format!("{}/{}", route.name, reference.guid);
+			if let Some(original) = self.posted.get(key.as_bytes())? {
+				let original = String::from_utf8(original.to_vec())?;
+				trace!(guid=%cap.guid, reference=%reference.guid, %original, "found posted reference");
+				if !originals.contains(&original) {
+					originals.push(original);
+				}
+			}
+		}
+
+		Ok(originals)
+	}
+
+	/// Record a CAP as posted, linked to the original alert it follows up on (or itself).
+	pub fn mark_posted(&self, route: &Route, cap: &Cap) -> Result<()> {
+		let original = cap.supersedes.first().unwrap_or(&cap.guid);
+		trace!(route=%route.name, guid=%cap.guid, %original, "marking as posted");
+		self.posted.insert(
+			format!("{}/{}", route.name, cap.guid).as_bytes(),
+			original.as_bytes(),
+		)?;
+		Ok(())
+	}
+
+	/// Whether a sink has already sent a CAP.
+	pub fn is_delivered(&self, sink: &str, cap: &Cap) -> Result<bool> {
+		Ok(self
+			.delivered
+			.contains_key(format!("{}/{}", sink, cap.guid).as_bytes())?)
+	}
+
+	/// Record that a sink has successfully sent a CAP.
+	pub fn mark_delivered(&self, sink: &str, cap: &Cap) -> Result<()> {
+		trace!(%sink, guid=%cap.guid, "marking as delivered");
+		self.delivered.insert(
+			format!("{}/{}", sink, cap.guid).as_bytes(),
+			Utc::now().to_rfc3339().as_bytes(),
+		)?;
+		Ok(())
+	}
+
+	/// Progress of sending a set of CAPs to a sink.
+	pub fn progress(&self, sink: &str, caps: &[Cap]) -> Progress {
+		Progress {
+			tree: self.progress.clone(),
+			key: format!(
+				"{}/{}",
+				sink,
+				caps.iter()
+					.map(|cap| cap.guid.as_str())
+					.collect::<Vec<_>>()
+					.join(",")
+			),
+		}
+	}
+
+	/// Record the messages a sink posted for a batch of alerts.
+	pub fn record_messages(
+		&self,
+		sink: &str,
+		caps: &[Cap],
+		messages: Vec<PostedMessage>,
+	) -> Result<()> {
+		let batch = match messages.first() {
+			Some(first) => format!("{}/{}", sink, first.id),
+			None => return Ok(()),
+		};
+
+		let posted = Posted {
+			alerts: caps
+				.iter()
+				.map(|cap| PostedAlert {
+					guid: cap.supersedes.first().unwrap_or(&cap.guid).clone(),
+					expires: cap.info().expires,
+					stale: false,
+				})
+				.collect(),
+			messages,
+		};
+
+		trace!(%batch, ?posted, "recording posted messages");
+		self.save_messages(&batch, &posted)?;
+		for alert in &posted.alerts {
+			self.message_index.insert(
+				format!("{}/{}", sink, alert.guid).as_bytes(),
+				batch.as_bytes(),
+			)?;
+		}
+
+		Ok(())
+	}
+
+	/// Find the batch of messages a sink posted for an original alert.
+	pub fn messages_for(&self, sink: &str, original: &str) -> Result<Option<(String, Posted)>> {
+		let batch = match self
+			.message_index
+			.get(format!("{}/{}", sink, original).as_bytes())?
+		{
+			Some(batch) => String::from_utf8(batch.to_vec())?,
+			None => return Ok(None),
+		};
+
+		Ok(match self.messages.get(batch.as_bytes())? {
+			Some(posted) => Some((batch, serde_json::from_slice(&posted)?)),
+			None => None,
+		})
+	}
+
+	/// All batches of messages a sink has posted and which are still tracked.
+	pub fn all_messages(&self, sink: &str) -> Result<Vec<(String, Posted)>> {
+		self.messages
+			.scan_prefix(format!("{}/", sink).as_bytes())
+			.map(|entry| {
+				let (batch, posted) = entry?;
+				Ok((
+					String::from_utf8(batch.to_vec())?,
+					serde_json::from_slice(&posted)?,
+				))
+			})
+			.collect()
+	}
+
+	pub fn save_messages(&self, batch: &str, posted: &Posted) -> Result<()> {
+		self.messages
+			.insert(batch.as_bytes(), serde_json::to_vec(posted)?)?;
+		Ok(())
+	}
+
+	/// Stop tracking a batch of messages, once it won't be edited anymore.
+	pub fn forget_messages(&self, sink: &str, batch: &str, posted: &Posted) -> Result<()> {
+		trace!(%batch, "forgetting posted messages");
+		self.messages.remove(batch.as_bytes())?;
+		for alert in &posted.alerts {
+			let key = format!("{}/{}", sink, alert.guid);
+			if self.message_index.get(key.as_bytes())?.as_deref() == Some(batch.as_bytes()) {
+				self.message_index.remove(key.as_bytes())?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+impl Progress {
+	/// The identifier of a part, if it was already sent with the same content.
+	pub fn sent(&self, part: usize, out: &Out) -> Result<Option<String>> {
+		let sent = match self.tree.get(self.part_key(part).as_bytes())? {
+			Some(value) => serde_json::from_slice::<SentPart>(&value)?,
+			None => return Ok(None),
+		};
+
+		Ok(if sent.digest == digest(out) {
+			debug!(key=%self.key, %part, "part was already sent, skipping");
+			Some(sent.id)
+		} else {
+			None
+		})
+	}
+
+	/// Record that a part was sent.
+	pub fn record(&self, part: usize, out: &Out, id: &str) -> Result<()> {
+		let sent = SentPart {
+			digest: digest(out),
+			id: id.into(),
+		};
+
+		trace!(key=%self.key, %part, ?sent, "recording sent part");
+		self.tree
+			.insert(self.part_key(part).as_bytes(), serde_json::to_vec(&sent)?)?;
+		Ok(())
+	}
+
+	/// Forget the parts once the whole send has gone through.
+	pub fn clear(&self) -> Result<()> {
+		for key in self
+			.tree
+			.scan_prefix(format!("{}/", self.key).as_bytes())
+			.keys()
+		{
+			self.tree.remove(key?)?;
+		}
+
+		Ok(())
+	}
+
+	fn part_key(&self, part: usize) -> String {
+		format!("{}/{}", self.key, part)
+	}
+}
+
+fn stuck_key(feed: &str, guid: &str) -> Vec<u8> {
+	format!("{}\0{}", feed, guid).into_bytes()
+}
+
+fn digest(out: &Out) -> String {
+	let mut hasher = Sha256::new();
+	hasher.update(out.message.as_bytes());
+	if let Some(ref image) = out.image {
+		hasher.update(image);
+	}
+
+	format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::alert;
+
+	#[test]
+	fn gives_up_on_stuck_items() {
+		let cache = Cache::temporary().unwrap();
+		let feed = "https://example.com/feed";
+		let item = Item {
+			guid: "one".into(),
+			link: "https://example.com/one.xml".into(),
+		};
+		let other = Item {
+			guid: "two".into(),
+			link: "https://example.com/two.xml".into(),
+		};
+
+		assert!(cache.discover(feed, &item, true).unwrap());
+		assert!(cache.discover(feed, &other, true).unwrap());
+		assert_eq!(
+			cache.stuck_items(feed).unwrap(),
+			vec![item.clone(), other.clone()]
+		);
+		assert!(cache
+			.stuck_items("https://example.com/other")
+			.unwrap()
+			.is_empty());
+
+		cache
+			.set_item_state(&other.guid, ItemState::Delivered)
+			.unwrap();
+		assert_eq!(cache.stuck_items(feed).unwrap(), vec![item.clone()]);
+		assert!(!cache.discover(feed, &other, true).unwrap());
+
+		for _ in 0..MAX_RETRIES {
+			assert!(cache.retry(&item).unwrap());
+		}
+		assert!(!cache.retry(&item).unwrap());
+		assert!(!cache.discover(feed, &item, true).unwrap());
+		assert!(cache.stuck_items(feed).unwrap().is_empty());
+	}
+
+	#[test]
+	fn progress_skips_sent_parts() {
+		let cache = Cache::temporary().unwrap();
+		let caps = [alert()];
+		let progress = cache.progress("default/discord", &caps);
+
+		let image = Out {
+			message: String::new(),
+			image: Some(b"fake png".to_vec()),
+		};
+		let text = Out {
+			message: "Heavy Rain Warning".into(),
+			image: None,
+		};
+
+		assert_eq!(progress.sent(0, &image).unwrap(), None);
+		progress.record(0, &image, "111").unwrap();
+
+		// a retry sees the same progress, for the same caps and sink only
+		let retry = cache.progress("default/discord", &caps);
+		assert_eq!(retry.sent(0, &image).unwrap().as_deref(), Some("111"));
+		assert_eq!(retry.sent(1, &text).unwrap(), None);
+		assert_eq!(
+			cache
+				.progress("default/matrix", &caps)
+				.sent(0, &image)
+				.unwrap(),
+			None
+		);
+
+		// a part with different content is sent again
+		let other = Out {
+			image: Some(b"other png".to_vec()),
+			..image.clone()
+		};
+		assert_eq!(retry.sent(0, &other).unwrap(), None);
+
+		retry.clear().unwrap();
+		assert_eq!(progress.sent(0, &image).unwrap(), None);
+	}
+}
@@ -2,21 +2,28 @@ use std::{
 	collections::HashMap,
 	hash::{Hash, Hasher},
 	iter::FromIterator,
-	num::ParseFloatError,
 	str::FromStr,
+	sync::{Arc, OnceLock},
 };
 
 use cheap_ruler::{CheapRuler, DistanceUnit};
 use chrono::{DateTime, Utc};
 use color_eyre::eyre::{eyre, Result};
-use geo::{CoordFloat, CoordNum, Coordinate, GeometryCollection, LineString, Point, Polygon};
+use geo::{
+	prelude::{Contains, ConvexHull, Intersects},
+	CoordFloat, CoordNum, Coordinate, GeometryCollection, LineString, Point, Polygon,
+};
+use geo_booleanop::boolean::BooleanOp;
 use geojson::{FeatureCollection, GeoJson};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use tracing::{debug, error, info, trace, warn};
+use tracing::{debug, error, info, info_span, trace, warn};
 
-use crate::feed::Item;
+use crate::{
+	feed::Item,
+	signature::{Verification, Verifier},
+};
 
-pub async fn fetch_cap(item: Item) -> Result<Cap> {
+pub async fn fetch_cap(item: Item, verifier: Option<Arc<Verifier>>) -> Result<Cap> {
 	let guid = item.guid;
 	info!(%guid, "fetching CAP");
 	let resp = reqwest::get(&item.link).await?;
@@ -38,10 +45,31 @@ pub async fn fetch_cap(item: Item) -> Result<Cap> {
 	debug!(%guid, chars=%body.chars().count(), "decoded body as text");
 	trace!(%guid, body=%body, "decoded body");
 
-	let mut cap: Cap = serde_xml_rs::from_str(&body)?;
+	let verification = match verifier {
+		Some(verifier) => verifier.check(&guid, &body)?,
+		None => None,
+	};
+
+	let mut cap: Cap = {
+		// so warnings from repairing polygons say which CAP they're about
+		let _span = info_span!("cap", %guid).entered();
+		serde_xml_rs::from_str(&body)?
+	};
 	trace!(%guid, ?cap, "parsed cap");
+	cap.item = guid.clone();
+	cap.verification = verification;
+
+	// cancellations often don't repeat what they cancel, but anything else needs saying
+	if cap.infos.is_empty() {
+		if cap.msg_type != MsgType::Cancel {
+			error!(%guid, "CAP has no info block");
+			return Err(eyre!("CAP {} has no info block", guid));
+		}
+
+		debug!(%guid, "cancellation has no info block");
+	}
 
-	for area in &mut cap.info.areas {
+	for area in cap.infos.iter_mut().flat_map(|i| &mut i.areas) {
 		for circle in &area.circles {
 			debug!(%guid, %circle, "converting circle to polygon");
 			if let Some(poly) = circle_to_polygon(circle) {
@@ -57,8 +85,9 @@ pub async fn fetch_cap(item: Item) -> Result<Cap> {
 
 	info!(
 		%guid,
-		about=%cap.info.headline,
-		areas=?cap.info.areas.iter().map(|a| &a.desc).collect::<Vec<_>>(),
+		about=%cap.info().headline,
+		areas=?cap.info().areas.iter().map(|a| &a.desc).collect::<Vec<_>>(),
+		languages=?cap.infos.iter().map(|i| &i.language).collect::<Vec<_>>(),
 		"parsed cap"
 	);
 
@@ -70,6 +99,9 @@ pub struct Cap {
 	#[serde(rename = "identifier")]
 	pub guid: String,
 
+	#[serde(default)]
+	pub sender: String,
+
 	#[serde(rename = "sent")]
 	pub date_sent: DateTime<Utc>,
 
@@ -77,9 +109,151 @@ pub struct Cap {
 	pub scope: String,
 
 	#[serde(rename = "msgType")]
-	pub msg_type: String,
+	pub msg_type: MsgType,
+
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub source: Option<String>,
+
+	/// Rule for limiting distribution, for the `Restricted` scope.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub restriction: Option<String>,
+
+	/// Intended recipients, for the `Private` scope.
+	#[serde(
+		default,
+		deserialize_with = "quoted_list_de",
+		skip_serializing_if = "Vec::is_empty"
+	)]
+	pub addresses: Vec<String>,
+
+	/// Special handling codes.
+	#[serde(default, rename = "code", skip_serializing_if = "Vec::is_empty")]
+	pub codes: Vec<String>,
+
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub note: Option<String>,
+
+	#[serde(default, deserialize_with = "references_de")]
+	pub references: Vec<Reference>,
+
+	/// Identifiers of incidents this alert is about, to group alerts together.
+	#[serde(
+		default,
+		deserialize_with = "quoted_list_de",
+		skip_serializing_if = "Vec::is_empty"
+	)]
+	pub incidents: Vec<String>,
+
+	/// One info block per language, in the order the sender gave them.
+	///
+	/// After [`Cap::select_language`], the preferred block comes first. Only cancellations can
+	/// have none.
+	#[serde(default, rename = "info")]
+	pub infos: Vec<Info>,
+
+	/// Identifiers of previously-posted alerts that this one updates or cancels.
+	///
+	/// This is filled in from the cache, not from the CAP itself.
+	#[serde(skip_deserializing, skip_serializing_if = "Vec::is_empty")]
+	pub supersedes: Vec<String>,
+
+	/// GUID of the feed item this CAP was found under.
+	#[serde(skip)]
+	pub item: String,
+
+	/// Outcome of checking the CAP's signature, when it's been checked and it's worth showing.
+	#[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+	pub verification: Option<Verification>,
+
+	/// Names of the boundaries the alert's areas touch.
+	///
+	/// This is filled in when filtering, not from the CAP itself.
+	#[serde(skip_deserializing, skip_serializing_if = "Vec::is_empty")]
+	pub affects: Vec<String>,
+
+	/// Set when this is a reminder about an alert that was already posted, rather than the alert itself.
+	#[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+	pub reminder: Option<Reminder>,
+}
+
+impl Cap {
+	/// The info block to render, or an empty one for a cancellation without any.
+	pub fn info(&self) -> &Info {
+		static EMPTY: OnceLock<Info> = OnceLock::new();
+		self.infos
+			.first()
+			.unwrap_or_else(|| EMPTY.get_or_init(Info::default))
+	}
+
+	/// Move the info block best matching the language preference list to the front.
+	///
+	/// Each preference matches a block with that exact language, or a more specific one
+	/// (`en` matches `en-NZ`). If nothing matches, the sender's first block is kept.
+	pub fn select_language(&mut self, preferences: &[String]) {
+		let found = preferences.iter().find_map(|pref| {
+			let pref = pref.to_lowercase();
+			self.infos
+				.iter()
+				.position(|i| i.language.to_lowercase() == pref)
+				.or_else(|| {
+					self.infos
+						.iter()
+						.position(|i| i.language.to_lowercase().starts_with(&format!("{}-", pref)))
+				})
+		});
+
+		if let Some(n) = found {
+			trace!(guid=%self.guid, language=%self.infos[n].language, "selected info block");
+			let info = self.infos.remove(n);
+			self.infos.insert(0, info);
+		}
+	}
+
+	/// A reminder about this alert, to post when its onset or expiry comes.
+	///
+	/// It has its own identifier, so sinks don't mistake it for the alert itself.
+	pub fn reminder(&self, kind: Reminder) -> Self {
+		let mut cap = self.clone();
+		cap.guid = format!("{}/{}", self.guid, kind.name());
+		cap.msg_type = MsgType::Alert;
+		cap.supersedes = Vec::new();
+		cap.reminder = Some(kind);
+
+		// sinks that build their own layout show just the headline, and when and where
+		for info in &mut cap.infos {
+			info.description = String::new();
+			info.instruction = String::new();
+		}
+
+		cap
+	}
 
-	pub info: Info,
+	/// Whether this is an update or cancellation of an alert that was already posted.
+	pub fn is_follow_up(&self) -> bool {
+		!self.supersedes.is_empty() && matches!(self.msg_type, MsgType::Update | MsgType::Cancel)
+	}
+
+	/// Fill in the polygons of areas that only have geocodes, from a lookup of known geocodes.
+	///
+	/// Areas that come with polygons are left alone, as those are more precise.
+	pub fn resolve_geocodes(&mut self, lookup: &HashMap<Geocode, Vec<Polygon<f64>>>) {
+		let guid = &self.guid;
+		for area in self.infos.iter_mut().flat_map(|i| &mut i.areas) {
+			if !area.polygons.is_empty() || area.geocodes.is_empty() {
+				continue;
+			}
+
+			for geocode in &area.geocodes {
+				match lookup.get(geocode) {
+					Some(polys) => {
+						trace!(%guid, area=%area.desc, ?geocode, polygons=%polys.len(), "resolved geocode");
+						area.polygons.extend(polys.iter().cloned());
+					}
+					None => debug!(%guid, area=%area.desc, ?geocode, "unknown geocode"),
+				}
+			}
+		}
+	}
 }
 
 impl Hash for Cap {
@@ -96,10 +270,48 @@ impl PartialEq<Self> for Cap {
 
 impl Eq for Cap {}
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum MsgType {
+	Alert,
+	Update,
+	Cancel,
+	Ack,
+	Error,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Reminder {
+	/// The alert is now in effect.
+	Onset,
+
+	/// The alert has lapsed.
+	Expiry,
+}
+
+impl Reminder {
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Onset => "onset",
+			Self::Expiry => "expiry",
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Reference {
+	pub sender: String,
+	pub guid: String,
+	pub sent: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Info {
-	#[serde(default)]
-	pub category: String,
+	#[serde(default = "default_language")]
+	pub language: String,
+
+	#[serde(default, rename = "category")]
+	pub categories: Vec<String>,
 	#[serde(default)]
 	pub event: String,
 	#[serde(default)]
@@ -107,8 +319,24 @@ pub struct Info {
 	pub severity: Severity,
 	#[serde(default)]
 	pub certainty: String,
-	pub onset: DateTime<Utc>,
-	pub expires: DateTime<Utc>,
+
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub audience: Option<String>,
+
+	#[serde(default, rename = "eventCode", deserialize_with = "parameters_de")]
+	pub event_codes: HashMap<String, String>,
+
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub effective: Option<DateTime<Utc>>,
+
+	/// When the subject event is expected to begin, if the sender said.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub onset: Option<DateTime<Utc>>,
+
+	/// When the alert stops being relevant. If not given, it's relevant until cancelled or updated.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub expires: Option<DateTime<Utc>>,
+
 	#[serde(default)]
 	pub headline: String,
 	#[serde(default)]
@@ -116,21 +344,59 @@ pub struct Info {
 	#[serde(default)]
 	pub instruction: String,
 
-	#[serde(default, rename = "responseType")]
-	pub response_type: String,
+	#[serde(
+		default,
+		rename = "responseType",
+		skip_serializing_if = "Vec::is_empty"
+	)]
+	pub response_types: Vec<String>,
 
 	#[serde(default, rename = "senderName")]
 	pub sender_name: String,
 
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub web: Option<String>,
+
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub contact: Option<String>,
+
 	#[serde(default, rename = "parameter", deserialize_with = "parameters_de")]
 	pub parameters: HashMap<String, String>,
 
+	#[serde(default, rename = "resource", skip_serializing_if = "Vec::is_empty")]
+	pub resources: Vec<Resource>,
+
 	#[serde(default, rename = "area")]
 	pub areas: Vec<Area>,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+/// A file with more information about an alert, like an image or audio.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Resource {
+	#[serde(rename = "resourceDesc")]
+	pub desc: String,
+
+	#[serde(rename = "mimeType")]
+	pub mime_type: String,
+
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub size: Option<u64>,
+
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub uri: Option<String>,
+
+	/// The file itself, base64-encoded, instead of a link.
+	#[serde(default, rename = "derefUri", skip_serializing_if = "Option::is_none")]
+	pub deref_uri: Option<String>,
+
+	/// SHA-1 hash of the file.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub digest: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Severity {
+	#[default]
 	Minor,
 	Moderate,
 	Severe,
@@ -167,6 +433,30 @@ pub struct Area {
 
 	#[serde(default, rename = "circle", skip_serializing_if = "Vec::is_empty")]
 	pub circles: Vec<String>,
+
+	#[serde(default, rename = "geocode", skip_serializing_if = "Vec::is_empty")]
+	pub geocodes: Vec<Geocode>,
+
+	/// Lower (or only) altitude of the area, in feet above sea level.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub altitude: Option<f64>,
+
+	/// Upper altitude of the area, in feet above sea level.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub ceiling: Option<f64>,
+}
+
+/// A code identifying an area in some scheme, like `UGC` or `SAME`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct Geocode {
+	#[serde(rename = "valueName")]
+	pub name: String,
+	pub value: String,
+}
+
+fn default_language() -> String {
+	// https://docs.oasis-open.org/emergency/cap/v1.2/CAP-v1.2-os.html#_Toc97699545
+	"en-US".into()
 }
 
 fn parameters_de<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
@@ -188,22 +478,78 @@ where
 	Ok(map)
 }
 
-fn polygons_de<'de, T, D>(deserializer: D) -> Result<Vec<Polygon<T>>, D::Error>
+/// Space-separated list, where items containing spaces are wrapped in double quotes.
+fn quoted_list_de<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	let text = String::deserialize(deserializer)?;
+	let mut items = Vec::new();
+	let mut item = String::new();
+	let mut quoted = false;
+	for c in text.chars() {
+		match c {
+			'"' => quoted = !quoted,
+			c if c.is_whitespace() && !quoted => {
+				if !item.is_empty() {
+					items.push(std::mem::take(&mut item));
+				}
+			}
+			c => item.push(c),
+		}
+	}
+
+	if !item.is_empty() {
+		items.push(item);
+	}
+
+	Ok(items)
+}
+
+fn references_de<'de, D>(deserializer: D) -> Result<Vec<Reference>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	use serde::de::Error;
+
+	let text = String::deserialize(deserializer)?;
+	trace!(%text, "parsing references");
+
+	// https://docs.oasis-open.org/emergency/cap/v1.2/CAP-v1.2-os.html#_Toc97699542
+	text.split_whitespace()
+		.map(|triplet| {
+			let mut parts = triplet.splitn(3, ',');
+			match (parts.next(), parts.next(), parts.next()) {
+				(Some(sender), Some(guid), Some(sent)) => Ok(Reference {
+					sender: sender.into(),
+					guid: guid.into(),
+					sent: DateTime::parse_from_rfc3339(sent)
+						.map_err(Error::custom)?
+						.with_timezone(&Utc),
+				}),
+				_ => Err(Error::custom("invalid reference")),
+			}
+		})
+		.collect()
+}
+
+fn polygons_de<'de, D>(deserializer: D) -> Result<Vec<Polygon<f64>>, D::Error>
 where
-	T: CoordNum + FromStr<Err = ParseFloatError>,
 	D: Deserializer<'de>,
 {
 	let texts = Vec::<String>::deserialize(deserializer)?;
 	trace!("parsing {} polygons", texts.len());
-	texts
+	Ok(texts
 		.into_iter()
-		.map(polygon::<'de, T, D>)
-		.collect::<Result<_, _>>()
+		.map(polygon::<'de, D>)
+		.collect::<Result<Vec<_>, _>>()?
+		.into_iter()
+		.flatten()
+		.collect())
 }
 
-fn polygon<'de, T, D>(text: String) -> Result<Polygon<T>, D::Error>
+fn polygon<'de, D>(text: String) -> Result<Vec<Polygon<f64>>, D::Error>
 where
-	T: CoordNum + FromStr<Err = ParseFloatError>,
 	D: Deserializer<'de>,
 {
 	use serde::de::Error;
@@ -216,20 +562,99 @@ where
 			let (y, x) = s
 				.split_once(',')
 				.ok_or_else(|| Error::custom("invalid coordinate pair"))?;
-			let x = x.parse::<T>().map_err(Error::custom)?;
-			let y = y.parse::<T>().map_err(Error::custom)?;
+			let x = x.parse::<f64>().map_err(Error::custom)?;
+			let y = y.parse::<f64>().map_err(Error::custom)?;
 			Ok(Coordinate { x, y })
 		})
 		.collect::<Result<Vec<_>, D::Error>>()?;
 	trace!(?coords, "parsed bunch of coordinates");
 
-	let line = LineString(coords);
+	repair(coords).map_err(Error::custom)
+}
+
+/// Fix up the common ways polygons in real-world feeds are broken, warning about each.
+///
+/// In order: repeated consecutive points are removed, swapped coordinates (latitude out of range
+/// while longitude isn't) are swapped back, unclosed rings are closed, and self-intersecting
+/// rings are untangled, which may split them into several polygons.
+fn repair(mut coords: Vec<Coordinate<f64>>) -> Result<Vec<Polygon<f64>>, String> {
+	let len = coords.len();
+	coords.dedup();
+	if coords.len() < len {
+		warn!(removed=%(len - coords.len()), "repaired polygon: removed repeated points");
+	}
+
+	if coords.iter().any(|c| c.y.abs() > 90.0) && coords.iter().all(|c| c.x.abs() <= 90.0) {
+		warn!("repaired polygon: latitude out of range, swapped coordinates to lat,lon");
+		for c in &mut coords {
+			std::mem::swap(&mut c.x, &mut c.y);
+		}
+	}
+
+	let mut line = LineString(coords);
 	if !line.is_closed() {
-		trace!(?line, "polygon is not closed");
-		return Err(Error::custom("polygon is not closed"));
+		warn!("repaired polygon: closed unclosed ring");
+		line.close();
 	}
 
-	Ok(Polygon::new(line, Vec::new()))
+	// a closed triangle is 4 points
+	if line.0.len() < 4 {
+		trace!(?line, "polygon has too few points");
+		return Err(format!("polygon has too few points ({})", line.0.len()));
+	}
+
+	let poly = Polygon::new(line, Vec::new());
+	if !self_intersects(poly.exterior()) {
+		return Ok(vec![poly]);
+	}
+
+	let untangled = poly.union(&poly);
+	if untangled.0.is_empty() {
+		warn!("repaired polygon: self-intersecting, replaced by its convex hull");
+		Ok(vec![poly.convex_hull()])
+	} else {
+		let parts = untangled
+			.0
+			.into_iter()
+			.flat_map(split_touching_lobes)
+			.collect::<Vec<_>>();
+		warn!(parts=%parts.len(), "repaired polygon: untangled self-intersections");
+		Ok(parts)
+	}
+}
+
+/// Lobes that only touch where the ring crossed itself come out of the union as holes of each
+/// other, so those "holes" that lie outside their exterior are made polygons of their own.
+fn split_touching_lobes(poly: Polygon<f64>) -> Vec<Polygon<f64>> {
+	let (exterior, interiors) = poly.into_inner();
+	let outer = Polygon::new(exterior, Vec::new());
+	let (holes, lobes): (Vec<_>, Vec<_>) = interiors
+		.into_iter()
+		.partition(|ring| ring.0.iter().any(|c| outer.contains(c)));
+
+	let (exterior, _) = outer.into_inner();
+	std::iter::once(Polygon::new(exterior, holes))
+		.chain(lobes.into_iter().map(|ring| Polygon::new(ring, Vec::new())))
+		.collect()
+}
+
+fn self_intersects(ring: &LineString<f64>) -> bool {
+	let lines = ring.lines().collect::<Vec<_>>();
+	let n = lines.len();
+	for i in 0..n {
+		// neighbouring segments share an endpoint, so skip them (including the last and first)
+		for j in (i + 2)..n {
+			if i == 0 && j == n - 1 {
+				continue;
+			}
+
+			if lines[i].intersects(&lines[j]) {
+				return true;
+			}
+		}
+	}
+
+	false
 }
 
 fn polygons_ser<S, T>(polys: &[Polygon<T>], serializer: S) -> Result<S::Ok, S::Error>
@@ -274,3 +699,223 @@ fn circle_to_polygon(circle: &str) -> Option<Polygon<f64>> {
 		Vec::new(),
 	))
 }
+
+#[cfg(test)]
+mod tests {
+	use geo::prelude::Area;
+
+	use super::*;
+	use crate::{
+		output::{headline, when_where},
+		testing::{alert, http_server},
+	};
+
+	const CANCEL: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<alert xmlns="urn:oasis:names:tc:emergency:cap:1.2">
+	<identifier>2.49.0.1.554.0.2021.10.17.0002</identifier>
+	<sender>alerts@metservice.com</sender>
+	<sent>2021-10-17T10:00:00+13:00</sent>
+	<status>Actual</status>
+	<msgType>Cancel</msgType>
+	<scope>Public</scope>
+	<note>Issued in error</note>
+	<references>alerts@metservice.com,2.49.0.1.554.0.2021.10.17.0001,2021-10-17T09:00:00+13:00</references>
+</alert>"#;
+
+	async fn fetch(xml: &'static str) -> Result<Cap> {
+		let (url, requests) = http_server(1, xml).await;
+		let cap = fetch_cap(
+			Item {
+				guid: "item".into(),
+				link: format!("{}/cap.xml", url),
+			},
+			None,
+		)
+		.await;
+		requests.await.unwrap();
+		cap
+	}
+
+	/// The fixture alert with an info block for each language, in that order.
+	fn multilingual(languages: &[&str]) -> Cap {
+		let mut cap = alert();
+		let info = cap.infos.remove(0);
+		cap.infos = languages
+			.iter()
+			.map(|language| Info {
+				language: (*language).into(),
+				..info.clone()
+			})
+			.collect();
+		cap
+	}
+
+	fn selected(languages: &[&str], preferences: &[&str]) -> Vec<String> {
+		let mut cap = multilingual(languages);
+		cap.select_language(
+			&preferences
+				.iter()
+				.map(|p| p.to_string())
+				.collect::<Vec<_>>(),
+		);
+		cap.infos.into_iter().map(|i| i.language).collect()
+	}
+
+	#[test]
+	fn selects_exact_language() {
+		assert_eq!(
+			selected(&["fr-CA", "en-NZ", "mi"], &["mi"]),
+			["mi", "fr-CA", "en-NZ"]
+		);
+		assert_eq!(selected(&["fr-CA", "en-NZ"], &["EN-nz"])[0], "en-NZ");
+
+		// an exact match wins over a more specific one, wherever they are
+		assert_eq!(selected(&["en-NZ", "en"], &["en"])[0], "en");
+	}
+
+	#[test]
+	fn selects_base_language() {
+		assert_eq!(
+			selected(&["mi", "fr-CA", "en-NZ"], &["en"]),
+			["en-NZ", "mi", "fr-CA"]
+		);
+
+		// only whole subtags match
+		assert_eq!(selected(&["mi", "en-NZ"], &["e"])[0], "mi");
+		assert_eq!(selected(&["mi", "en-NZ"], &["en-N"])[0], "mi");
+	}
+
+	#[test]
+	fn follows_preference_order() {
+		assert_eq!(
+			selected(&["en-NZ", "mi", "fr"], &["de", "mi", "en"])[0],
+			"mi"
+		);
+		assert_eq!(selected(&["en-NZ", "mi", "fr"], &["fr", "mi"])[0], "fr");
+		assert_eq!(selected(&["mi-NZ", "en-NZ"], &["en", "mi"])[0], "en-NZ");
+	}
+
+	#[test]
+	fn falls_back_to_first_block() {
+		assert_eq!(selected(&["fr-CA", "en-NZ"], &["de"]), ["fr-CA", "en-NZ"]);
+		assert_eq!(selected(&["fr-CA", "en-NZ"], &[]), ["fr-CA", "en-NZ"]);
+
+		let mut cap = multilingual(&[]);
+		cap.select_language(&["en".into()]);
+		assert!(cap.infos.is_empty());
+	}
+
+	#[tokio::test]
+	async fn accepts_cancellation_without_info() {
+		let cap = fetch(CANCEL).await.unwrap();
+		assert_eq!(cap.msg_type, MsgType::Cancel);
+		assert!(cap.infos.is_empty());
+		assert_eq!(cap.references.len(), 1);
+		assert!(cap.info().headline.is_empty());
+		assert!(cap.info().areas.is_empty());
+	}
+
+	#[tokio::test]
+	async fn rejects_alert_without_info() {
+		let alert = Box::leak(CANCEL.replace("Cancel", "Alert").into_boxed_str());
+		assert!(fetch(alert).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn renders_cancellation_without_info() {
+		let mut cap = fetch(CANCEL).await.unwrap();
+		cap.supersedes = vec!["2.49.0.1.554.0.2021.10.17.0001".into()];
+		assert_eq!(headline(&cap), "CANCELLED: ISSUED IN ERROR");
+		assert_eq!(when_where(&cap), "");
+
+		cap.note = None;
+		assert_eq!(headline(&cap), "CANCELLED: ALERT");
+	}
+
+	/// Coordinates from a CAP polygon, as `lat,lon` pairs.
+	fn coords(text: &str) -> Vec<Coordinate<f64>> {
+		text.split_whitespace()
+			.map(|pair| {
+				let (y, x) = pair.split_once(',').unwrap();
+				Coordinate {
+					x: x.parse().unwrap(),
+					y: y.parse().unwrap(),
+				}
+			})
+			.collect()
+	}
+
+	fn ring(text: &str) -> LineString<f64> {
+		LineString(coords(text))
+	}
+
+	const SQUARE: &str = "-41,175 -40,175 -40,176 -41,176 -41,175";
+
+	#[test]
+	fn repair_leaves_valid_polygon_alone() {
+		let polys = repair(coords(SQUARE)).unwrap();
+		assert_eq!(polys, vec![Polygon::new(ring(SQUARE), Vec::new())]);
+	}
+
+	#[test]
+	fn repair_removes_repeated_points() {
+		let polys = repair(coords(
+			"-41,175 -40,175 -40,175 -40,176 -41,176 -41,176 -41,176 -41,175",
+		))
+		.unwrap();
+		assert_eq!(polys, vec![Polygon::new(ring(SQUARE), Vec::new())]);
+	}
+
+	#[test]
+	fn repair_swaps_lon_lat() {
+		let polys = repair(coords("175,-41 175,-40 176,-40 176,-41 175,-41")).unwrap();
+		assert_eq!(polys, vec![Polygon::new(ring(SQUARE), Vec::new())]);
+	}
+
+	#[test]
+	fn repair_keeps_lat_lon_near_the_poles() {
+		// both in range, so there's no telling they're swapped
+		let polar = "-80,10 -80,20 -85,20 -85,10 -80,10";
+		let polys = repair(coords(polar)).unwrap();
+		assert_eq!(polys, vec![Polygon::new(ring(polar), Vec::new())]);
+	}
+
+	#[test]
+	fn repair_closes_open_ring() {
+		let polys = repair(coords("-41,175 -40,175 -40,176 -41,176")).unwrap();
+		assert_eq!(polys, vec![Polygon::new(ring(SQUARE), Vec::new())]);
+	}
+
+	#[test]
+	fn repair_rejects_too_few_points() {
+		assert!(repair(coords("-41,175 -40,175")).is_err());
+		assert!(repair(coords("-41,175 -40,175 -40,175 -41,175")).is_err());
+	}
+
+	#[test]
+	fn repair_untangles_bow_tie() {
+		let bow_tie = "-41,175 -40,176 -40,175 -41,176 -41,175";
+		assert!(self_intersects(&ring(bow_tie)));
+
+		let polys = repair(coords(bow_tie)).unwrap();
+		assert_eq!(polys.len(), 2);
+		for poly in &polys {
+			assert!(!self_intersects(poly.exterior()));
+		}
+
+		// two triangles, meeting where the ring crossed itself
+		let area = polys.iter().map(|p| p.unsigned_area()).sum::<f64>();
+		assert!((area - 0.5).abs() < 1e-9, "area is {}", area);
+	}
+
+	#[test]
+	fn self_intersects_ignores_neighbouring_segments() {
+		assert!(!self_intersects(&ring(SQUARE)));
+		assert!(!self_intersects(&ring(
+			"-41,175 -40,175 -40.5,175.5 -40,176 -41,176 -41,175"
+		)));
+		assert!(self_intersects(&ring(
+			"-41,175 -40,175 -41,176 -40,176 -41,175"
+		)));
+	}
+}
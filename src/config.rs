@@ -0,0 +1,529 @@
+use std::{
+	collections::{HashMap, HashSet},
+	path::PathBuf,
+	str::FromStr,
+};
+
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Deserializer};
+use tokio::fs::read_to_string;
+use tracing::{debug, trace};
+
+use crate::{
+	cap::Severity, discord::StaleAction, email::SmtpSecurity, filter::Filter, geodirs::Overlap,
+	output::OutputFormat, webhook::ImageMode, Args,
+};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+	#[serde(default, rename = "route")]
+	pub routes: Vec<Route>,
+
+	/// Webhook to alert about problems with the feeds themselves, like CAPs that fail to parse.
+	#[serde(default)]
+	pub admin: Option<WebhookSink>,
+}
+
+/// A set of feeds, filtered and formatted one way, and sent to some sinks.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Route {
+	pub name: String,
+	pub feeds: Vec<String>,
+
+	#[serde(deserialize_with = "from_str")]
+	pub severity: Severity,
+
+	/// Statuses of alerts to post, like `Actual` or `Exercise`.
+	#[serde(rename = "status")]
+	pub statuses: Vec<String>,
+
+	/// Scopes of alerts to post, like `Public` or `Restricted`.
+	#[serde(rename = "scope")]
+	pub scopes: Vec<String>,
+
+	#[serde(deserialize_with = "from_str_opt")]
+	pub filter: Option<Filter>,
+
+	/// Maximum age of alerts to post, in hours.
+	pub max_age: Option<u32>,
+
+	/// In watch mode, post reminders when alerts come into effect and when they expire.
+	pub reminders: bool,
+
+	pub language: Vec<String>,
+	pub boundaries: PathBuf,
+
+	/// Distance to grow boundaries by, in kilometres.
+	pub buffer: f64,
+
+	#[serde(deserialize_with = "from_str_opt")]
+	pub min_overlap: Option<Overlap>,
+
+	pub outlines: PathBuf,
+	pub geocodes: PathBuf,
+
+	/// Geocode names to index the geocodes folder by, each `NAME` or `NAME=PROPERTY`.
+	pub geocode_keys: Vec<String>,
+
+	#[serde(deserialize_with = "from_str")]
+	pub format: OutputFormat,
+
+	pub image_height: u32,
+	pub image_width: u32,
+
+	pub print: bool,
+	pub file: Option<PathBuf>,
+	pub facebook: Option<FacebookSink>,
+	pub discord: Option<DiscordSink>,
+	pub slack: Option<SlackSink>,
+	pub teams: Option<TeamsSink>,
+	pub matrix: Option<MatrixSink>,
+	pub telegram: Option<TelegramSink>,
+	pub webhook: Option<WebhookSink>,
+	pub email: Option<EmailSink>,
+	pub push: Option<PushSink>,
+	pub mastodon: Option<MastodonSink>,
+}
+
+impl Default for Route {
+	fn default() -> Self {
+		Self {
+			name: String::new(),
+			feeds: Vec::new(),
+			severity: Severity::Minor,
+			statuses: vec!["Actual".into()],
+			scopes: vec!["Public".into()],
+			filter: None,
+			max_age: None,
+			reminders: false,
+			language: Vec::new(),
+			boundaries: "_boundaries".into(),
+			buffer: 0.0,
+			min_overlap: None,
+			outlines: "_outlines".into(),
+			geocodes: "_geocodes".into(),
+			geocode_keys: Vec::new(),
+			format: OutputFormat::Map,
+			image_height: 512,
+			image_width: 512,
+			print: false,
+			file: None,
+			facebook: None,
+			discord: None,
+			slack: None,
+			teams: None,
+			matrix: None,
+			telegram: None,
+			webhook: None,
+			email: None,
+			push: None,
+			mastodon: None,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FacebookSink {
+	pub token: String,
+	pub thread: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscordSink {
+	pub webhook_url: String,
+
+	#[serde(default = "default_stale", deserialize_with = "from_str")]
+	pub stale: StaleAction,
+}
+
+fn default_stale() -> StaleAction {
+	StaleAction::Strike
+}
+
+/// Either `webhook_url`, or `token` and `channel` (which also uploads maps).
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SlackSink {
+	pub webhook_url: Option<String>,
+	pub token: Option<String>,
+	pub channel: Option<String>,
+
+	#[serde(default = "default_slack_api_url")]
+	pub api_url: String,
+}
+
+fn default_slack_api_url() -> String {
+	"https://slack.com/api".into()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TeamsSink {
+	pub webhook_url: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatrixSink {
+	pub homeserver: String,
+	pub access_token: String,
+	pub room_id: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TelegramSink {
+	pub token: String,
+	pub chat_id: String,
+
+	#[serde(default = "default_telegram_api_url")]
+	pub api_url: String,
+}
+
+fn default_telegram_api_url() -> String {
+	"https://api.telegram.org".into()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookSink {
+	pub url: String,
+
+	/// Handlebars template for the request body.
+	pub template: Option<PathBuf>,
+
+	#[serde(default)]
+	pub headers: HashMap<String, String>,
+
+	/// Key to sign the body with (HMAC-SHA256).
+	pub secret: Option<String>,
+
+	#[serde(default = "default_signature_header")]
+	pub signature_header: String,
+
+	#[serde(default = "default_image_mode", deserialize_with = "from_str")]
+	pub image: ImageMode,
+}
+
+fn default_signature_header() -> String {
+	"X-Capchat-Signature".into()
+}
+
+fn default_image_mode() -> ImageMode {
+	ImageMode::None
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailSink {
+	pub host: String,
+
+	#[serde(default = "default_smtp_port")]
+	pub port: u16,
+
+	#[serde(default = "default_smtp_security", deserialize_with = "from_str")]
+	pub security: SmtpSecurity,
+
+	pub username: Option<String>,
+	pub password: Option<String>,
+
+	pub from: String,
+	pub to: Vec<String>,
+}
+
+fn default_smtp_port() -> u16 {
+	587
+}
+
+fn default_smtp_security() -> SmtpSecurity {
+	SmtpSecurity::StartTls
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PushSink {
+	pub url: String,
+	pub token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MastodonSink {
+	pub instance: String,
+	pub access_token: String,
+	pub visibility: Option<String>,
+}
+
+impl Route {
+	/// Names of the sinks this route sends to, in the order they're sent to.
+	pub fn sinks(&self) -> Vec<&'static str> {
+		let configured = [
+			("print", self.print),
+			("file", self.file.is_some()),
+			("facebook", self.facebook.is_some()),
+			("slack", self.slack.is_some()),
+			("teams", self.teams.is_some()),
+			("matrix", self.matrix.is_some()),
+			("telegram", self.telegram.is_some()),
+			("webhook", self.webhook.is_some()),
+			("email", self.email.is_some()),
+			("push", self.push.is_some()),
+			("mastodon", self.mastodon.is_some()),
+			("discord", self.discord.is_some()),
+		];
+
+		configured
+			.iter()
+			.filter(|(_, on)| *on)
+			.map(|(name, _)| *name)
+			.collect()
+	}
+
+	/// The implicit route made of the command-line options.
+	pub fn from_args(args: &Args) -> Self {
+		Self {
+			name: "default".into(),
+			feeds: args.cap.clone(),
+			severity: args.severity,
+			statuses: args.status.clone(),
+			scopes: args.scope.clone(),
+			filter: args.filter.clone(),
+			max_age: args.max_age,
+			reminders: args.reminders,
+			language: args.language.clone(),
+			boundaries: args.boundaries.clone(),
+			buffer: args.buffer,
+			min_overlap: args.min_overlap,
+			outlines: args.outlines.clone(),
+			geocodes: args.geocodes.clone(),
+			geocode_keys: args.geocode_key.clone(),
+			format: args.format,
+			image_height: args.image_height,
+			image_width: args.image_width,
+			print: args.print,
+			file: args.file.clone(),
+			facebook: match (&args.facebook_token, &args.facebook_thread) {
+				(Some(token), Some(thread)) => Some(FacebookSink {
+					token: token.clone(),
+					thread: thread.clone(),
+				}),
+				_ => None,
+			},
+			discord: args.discord_webhook_url.as_ref().map(|url| DiscordSink {
+				webhook_url: url.clone(),
+				stale: args.discord_stale,
+			}),
+			slack: if args.slack_webhook_url.is_some()
+				|| (args.slack_token.is_some() && args.slack_channel.is_some())
+			{
+				Some(SlackSink {
+					webhook_url: args.slack_webhook_url.clone(),
+					token: args.slack_token.clone(),
+					channel: args.slack_channel.clone(),
+					api_url: args.slack_api_url.clone(),
+				})
+			} else {
+				None
+			},
+			teams: args.teams_webhook_url.as_ref().map(|url| TeamsSink {
+				webhook_url: url.clone(),
+			}),
+			matrix: match (
+				&args.matrix_homeserver,
+				&args.matrix_token,
+				&args.matrix_room,
+			) {
+				(Some(homeserver), Some(token), Some(room)) => Some(MatrixSink {
+					homeserver: homeserver.clone(),
+					access_token: token.clone(),
+					room_id: room.clone(),
+				}),
+				_ => None,
+			},
+			telegram: match (&args.telegram_token, &args.telegram_chat) {
+				(Some(token), Some(chat)) => Some(TelegramSink {
+					token: token.clone(),
+					chat_id: chat.clone(),
+					api_url: args.telegram_api_url.clone(),
+				}),
+				_ => None,
+			},
+			webhook: args.webhook_url.as_ref().map(|url| WebhookSink {
+				url: url.clone(),
+				template: args.webhook_template.clone(),
+				headers: args.webhook_header.iter().cloned().collect(),
+				secret: args.webhook_secret.clone(),
+				signature_header: args.webhook_signature_header.clone(),
+				image: args.webhook_image,
+			}),
+			email: match (&args.smtp_host, &args.email_from) {
+				(Some(host), Some(from)) if !args.email_to.is_empty() => Some(EmailSink {
+					host: host.clone(),
+					port: args.smtp_port,
+					security: args.smtp_security,
+					username: args.smtp_username.clone(),
+					password: args.smtp_password.clone(),
+					from: from.clone(),
+					to: args.email_to.clone(),
+				}),
+				_ => None,
+			},
+			push: args.push_url.as_ref().map(|url| PushSink {
+				url: url.clone(),
+				token: args.push_token.clone(),
+			}),
+			mastodon: match (&args.mastodon_instance, &args.mastodon_token) {
+				(Some(instance), Some(token)) => Some(MastodonSink {
+					instance: instance.clone(),
+					access_token: token.clone(),
+					visibility: args.mastodon_visibility.clone(),
+				}),
+				_ => None,
+			},
+		}
+	}
+}
+
+/// The config file, if any, plus the implicit route and admin webhook from the command line.
+pub async fn load(args: &Args) -> Result<Config> {
+	let mut config = if let Some(path) = &args.config {
+		debug!(?path, "reading config file");
+		let config: Config = toml::from_str(&read_to_string(path).await?)?;
+		trace!(?config, "parsed config");
+		config
+	} else {
+		Config::default()
+	};
+
+	if !args.cap.is_empty() {
+		config.routes.push(Route::from_args(args));
+	}
+
+	if let Some(url) = &args.admin_webhook_url {
+		config.admin = Some(WebhookSink {
+			url: url.clone(),
+			template: args.admin_webhook_template.clone(),
+			headers: HashMap::new(),
+			secret: None,
+			signature_header: default_signature_header(),
+			image: ImageMode::None,
+		});
+	}
+
+	let mut names = HashSet::new();
+	for route in &config.routes {
+		if route.name.is_empty() {
+			return Err(eyre!("routes must have a name"));
+		}
+
+		if !names.insert(&route.name) {
+			return Err(eyre!("duplicate route name: {}", route.name));
+		}
+	}
+
+	debug!(routes=?names, "loaded routes");
+	Ok(config)
+}
+
+/// Parse a `Name: value` header from the command line.
+pub fn parse_header(s: &str) -> Result<(String, String), String> {
+	s.split_once(':')
+		.map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+		.ok_or_else(|| format!("invalid header, expected `Name: value`: {}", s))
+}
+
+fn from_str<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+	T: FromStr<Err = String>,
+	D: Deserializer<'de>,
+{
+	use serde::de::Error;
+
+	String::deserialize(deserializer)?
+		.parse()
+		.map_err(Error::custom)
+}
+
+fn from_str_opt<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+	T: FromStr<Err = String>,
+	D: Deserializer<'de>,
+{
+	use serde::de::Error;
+
+	Option::<String>::deserialize(deserializer)?
+		.map(|s| s.parse())
+		.transpose()
+		.map_err(Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The TOML examples in the README, which should all stay valid.
+	fn readme_examples() -> Vec<&'static str> {
+		include_str!("../README.md")
+			.split("```toml\n")
+			.skip(1)
+			.map(|block| block.split("```").next().unwrap())
+			.collect()
+	}
+
+	#[test]
+	fn loads_readme_examples() {
+		let examples = readme_examples();
+		assert_eq!(examples.len(), 3);
+		for example in examples {
+			if let Err(err) = toml::from_str::<Config>(example) {
+				panic!("failed to parse README example: {}\n{}", err, example);
+			}
+		}
+
+		let config: Config = toml::from_str(readme_examples()[1]).unwrap();
+		assert_eq!(config.routes.len(), 2);
+		assert_eq!(config.routes[0].name, "auckland-ops");
+		assert_eq!(config.routes[0].format, OutputFormat::Map);
+		assert!(matches!(
+			config.routes[0].discord,
+			Some(DiscordSink {
+				stale: StaleAction::Strike,
+				..
+			})
+		));
+		assert_eq!(config.routes[1].sinks(), ["print", "file", "facebook"]);
+	}
+
+	#[test]
+	fn rejects_unknown_keys() {
+		let route = r#"
+			[[route]]
+			name = "typo"
+			feeds = ["https://alerts.metservice.com/cap/rss"]
+			severty = "Severe"
+		"#;
+		let err = toml::from_str::<Config>(route).unwrap_err();
+		assert!(err.to_string().contains("severty"), "{}", err);
+
+		let sink = r#"
+			[[route]]
+			name = "typo"
+
+			[route.discord]
+			webhook_url = "https://discord.com/api/webhooks/..."
+			stail = "delete"
+		"#;
+		let err = toml::from_str::<Config>(sink).unwrap_err();
+		assert!(err.to_string().contains("stail"), "{}", err);
+
+		let top = r#"
+			[admn]
+			url = "https://discord.com/api/webhooks/..."
+		"#;
+		assert!(toml::from_str::<Config>(top).is_err());
+	}
+}
@@ -1,27 +1,293 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, iter::once, str::FromStr};
 
+use chrono::Utc;
 use color_eyre::eyre::{eyre, Result};
 use reqwest::{
 	multipart::{Form, Part},
-	Client,
+	Client, RequestBuilder, StatusCode, Url,
 };
 use serde::Serialize;
 use serde_json::Value;
-use tracing::trace;
+use tracing::{debug, error, info, trace};
 
-use crate::output::{split_long_message, Out};
+use crate::{
+	cache::{Cache, Posted, PostedMessage, Progress},
+	cap::{Cap, MsgType},
+	config::{DiscordSink, Route},
+	geodirs::Geodata,
+	output::{render, split_long_message, Out},
+	retry,
+};
+
+/// Maximum length of a message, leaving room to strike it through later.
+const MAX_LEN: usize = 2000 - 4;
+
+/// Discord's error code for a message that doesn't exist (anymore).
+const UNKNOWN_MESSAGE: u64 = 10008;
+
+/// A message we tried to edit or delete is gone, e.g. because a moderator deleted it.
+#[derive(Clone, Copy, Debug)]
+struct UnknownMessage;
+
+impl fmt::Display for UnknownMessage {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "discord message no longer exists")
+	}
+}
+
+impl std::error::Error for UnknownMessage {}
+
+fn is_unknown_message(status: StatusCode, body: &str) -> bool {
+	status == StatusCode::NOT_FOUND
+		|| serde_json::from_str::<Value>(body)
+			.ok()
+			.and_then(|body| body.get("code")?.as_u64())
+			== Some(UNKNOWN_MESSAGE)
+}
+
+/// What to do with messages for alerts that were cancelled or have expired.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StaleAction {
+	Strike,
+	Delete,
+}
+
+impl FromStr for StaleAction {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_lowercase().as_str() {
+			"strike" => Ok(Self::Strike),
+			"delete" => Ok(Self::Delete),
+			_ => Err(format!("unknown stale action: {}", s)),
+		}
+	}
+}
+
+/// Post alerts, editing the messages of previously-posted alerts where possible.
+///
+/// An update edits the original messages if they were for that alert alone. A cancellation strikes
+/// through or deletes the original messages once every alert they were for is stale. Anything else
+/// is posted as new messages (rendered as a follow-up by the output), which are tracked for later.
+pub async fn post(
+	route: &Route,
+	sink: &DiscordSink,
+	cache: &Cache,
+	geo: &Geodata,
+	caps: &[Cap],
+	out: &Out,
+	progress: &Progress,
+) -> Result<()> {
+	let webhook_url = sink.webhook_url.as_str();
+	let key = sink_key(route);
+
+	let mut fresh = Vec::with_capacity(caps.len());
+	for cap in caps {
+		let original = match cap.supersedes.first() {
+			Some(original) if cap.is_follow_up() => original,
+			_ => {
+				fresh.push(cap.clone());
+				continue;
+			}
+		};
+
+		let (batch, mut posted) = match cache.messages_for(&key, original)? {
+			Some(found) => found,
+			None => {
+				fresh.push(cap.clone());
+				continue;
+			}
+		};
+
+		let alone = posted.alerts.iter().all(|a| a.guid == *original);
+		if cap.msg_type == MsgType::Update && alone {
+			info!(guid=%cap.guid, %original, "editing discord messages for update");
+			let out = render(once(cap.clone()).collect(), route, geo).await?;
+			match edit(webhook_url, &posted.messages, &out).await {
+				Ok(messages) => {
+					posted.messages = messages;
+					for alert in &mut posted.alerts {
+						alert.expires = cap.info().expires;
+					}
+					cache.save_messages(&batch, &posted)?;
+				}
+				// there's nothing left to edit, so the update is posted on its own instead
+				Err(err) if err.downcast_ref::<UnknownMessage>().is_some() => {
+					info!(guid=%cap.guid, %original, "original discord message is gone, posting update as new");
+					cache.forget_messages(&key, &batch, &posted)?;
+					fresh.push(cap.clone());
+				}
+				Err(err) => return Err(err),
+			}
+			continue;
+		}
+
+		for alert in posted.alerts.iter_mut().filter(|a| a.guid == *original) {
+			alert.stale = true;
+		}
+
+		let retired = retire(route, sink, cache, &batch, &posted).await?;
+		if !retired || cap.msg_type != MsgType::Cancel {
+			fresh.push(cap.clone());
+		}
+	}
+
+	if fresh.is_empty() {
+		return Ok(());
+	}
+
+	let messages = if fresh.len() == caps.len() {
+		send(webhook_url, out, progress).await?
+	} else {
+		let out = render(fresh.iter().cloned().collect(), route, geo).await?;
+		send(webhook_url, &out, progress).await?
+	};
 
-pub async fn send(webhook_url: &str, out: &Out) -> Result<()> {
+	// reminders are one-off notices, so there's nothing to edit or retire later
+	if fresh.iter().all(|cap| cap.reminder.is_some()) {
+		return Ok(());
+	}
+
+	cache.record_messages(&key, &fresh, messages)
+}
+
+/// Retire messages for alerts that have expired.
+///
+/// A batch that fails to be retired doesn't stop the others; it's tried again on the next sweep.
+pub async fn sweep(route: &Route, sink: &DiscordSink, cache: &Cache) -> Result<()> {
+	let now = Utc::now();
+	let mut failed = Vec::new();
+	for (batch, mut posted) in cache.all_messages(&sink_key(route))? {
+		let mut changed = false;
+		for alert in posted
+			.alerts
+			.iter_mut()
+			.filter(|a| !a.stale && a.expires.is_some_and(|e| e < now))
+		{
+			debug!(guid=%alert.guid, expires=?alert.expires, "alert has expired");
+			alert.stale = true;
+			changed = true;
+		}
+
+		if changed {
+			if let Err(err) = retire(route, sink, cache, &batch, &posted).await {
+				error!(%batch, "failed to retire discord messages: {:?}", err);
+				failed.push(batch);
+			}
+		}
+	}
+
+	if failed.is_empty() {
+		Ok(())
+	} else {
+		Err(eyre!(
+			"failed to retire discord messages: {}",
+			failed.join(", ")
+		))
+	}
+}
+
+/// Strike through or delete a batch of messages if all of its alerts are stale.
+///
+/// Returns whether the batch was retired.
+async fn retire(
+	route: &Route,
+	sink: &DiscordSink,
+	cache: &Cache,
+	batch: &str,
+	posted: &Posted,
+) -> Result<bool> {
+	if !posted.alerts.iter().all(|a| a.stale) {
+		debug!(%batch, "some alerts in batch are still current, keeping messages");
+		cache.save_messages(batch, posted)?;
+		return Ok(false);
+	}
+
+	info!(%batch, action=?sink.stale, "retiring stale discord messages");
+	for message in &posted.messages {
+		let res = match sink.stale {
+			StaleAction::Delete => delete_message(&sink.webhook_url, &message.id).await,
+			StaleAction::Strike if !message.image => {
+				let struck = Out {
+					message: format!("~~{}~~", message.content),
+					image: None,
+				};
+				edit_message(&sink.webhook_url, &message.id, &struck)
+					.await
+					.map(drop)
+			}
+			StaleAction::Strike => Ok(()),
+		};
+
+		// a message someone already deleted is as good as retired, and would otherwise fail forever
+		match res {
+			Err(err) if err.downcast_ref::<UnknownMessage>().is_some() => {
+				debug!(%batch, id=%message.id, "discord message is already gone");
+			}
+			res => res?,
+		}
+	}
+
+	cache.forget_messages(&sink_key(route), batch, posted)?;
+	Ok(true)
+}
+
+fn sink_key(route: &Route) -> String {
+	format!("{}/discord", route.name)
+}
+
+pub async fn send(webhook_url: &str, out: &Out, progress: &Progress) -> Result<Vec<PostedMessage>> {
+	let mut messages = Vec::new();
+	for (i, part) in parts(out).into_iter().enumerate() {
+		let message = match progress.sent(i, &part)? {
+			Some(id) => PostedMessage {
+				id,
+				content: part.message.clone(),
+				image: part.image.is_some(),
+			},
+			None => {
+				let message = send_message(webhook_url, &part).await?;
+				progress.record(i, &part, &message.id)?;
+				message
+			}
+		};
+		messages.push(message);
+	}
+
+	Ok(messages)
+}
+
+/// Edit previously-posted messages to show new output, posting or deleting as needed.
+async fn edit(webhook_url: &str, old: &[PostedMessage], out: &Out) -> Result<Vec<PostedMessage>> {
+	let new = parts(out);
+	let mut messages = Vec::with_capacity(new.len());
+	for i in 0..old.len().max(new.len()) {
+		match (old.get(i), new.get(i)) {
+			(Some(o), Some(n)) => messages.push(edit_message(webhook_url, &o.id, n).await?),
+			(None, Some(n)) => messages.push(send_message(webhook_url, n).await?),
+			(Some(o), None) => match delete_message(webhook_url, &o.id).await {
+				Err(err) if err.downcast_ref::<UnknownMessage>().is_none() => return Err(err),
+				_ => {}
+			},
+			(None, None) => unreachable!(),
+		}
+	}
+
+	Ok(messages)
+}
+
+/// Split output into an image-only part, if there's an image, followed by text parts.
+fn parts(out: &Out) -> Vec<Out> {
+	let mut parts = Vec::new();
 	let mut out = if let Out {
 		message,
 		image: Some(img),
 	} = out
 	{
-		let image_only = Out {
+		parts.push(Out {
 			message: "".into(),
 			image: Some(img.clone()),
-		};
-		send_message(webhook_url, &image_only).await?;
+		});
 		Out {
 			message: message.clone(),
 			image: None,
@@ -31,55 +297,131 @@ pub async fn send(webhook_url: &str, out: &Out) -> Result<()> {
 	};
 
 	loop {
-		let (first, rest) = split_long_message(out, 2000, 280);
-		send_message(webhook_url, &first).await?;
+		let (first, rest) = split_long_message(out, MAX_LEN, 280);
+		parts.push(first);
 		out = match rest {
 			Some(o) => o,
 			None => break,
 		};
 	}
 
-	Ok(())
+	parts
 }
 
-async fn send_message(webhook_url: &str, out: &Out) -> Result<()> {
+fn message_url(webhook_url: &str, id: &str) -> Result<Url> {
+	let mut url = Url::parse(webhook_url)?;
+	url.path_segments_mut()
+		.map_err(|_| eyre!("invalid discord webhook url"))?
+		.extend(&["messages", id]);
+	Ok(url)
+}
+
+async fn send_message(webhook_url: &str, out: &Out) -> Result<PostedMessage> {
 	let client = Client::new();
-	let req = client.post(webhook_url);
+	request(
+		|| client.post(webhook_url).query(&[("wait", "true")]),
+		out,
+		false,
+	)
+	.await
+}
 
-	let req = if let Some(ref imagedata) = out.image {
-		assert!(out.message.is_empty(), "cannot send both text and an image");
+async fn edit_message(webhook_url: &str, id: &str, out: &Out) -> Result<PostedMessage> {
+	let client = Client::new();
+	let url = message_url(webhook_url, id)?;
+	request(|| client.patch(url.clone()), out, true).await
+}
+
+async fn delete_message(webhook_url: &str, id: &str) -> Result<()> {
+	let client = Client::new();
+	let url = message_url(webhook_url, id)?;
+	let resp = retry::send("discord", || Ok(client.delete(url.clone()))).await?;
+	trace!(?resp, "response from discord");
 
-		let part = Part::bytes(imagedata.clone())
-			.file_name("image.png")
-			.mime_str("image/png")?;
-		let form = Form::new().part("file", part);
+	let status = resp.status();
+	if !status.is_success() {
+		let body = resp.text().await?;
+		if is_unknown_message(status, &body) {
+			return Err(UnknownMessage.into());
+		}
 
-		req.multipart(form)
+		Err(eyre!(
+			"failed to delete message from discord: {}\n{:?}",
+			status,
+			body
+		))
 	} else {
-		req.header("Content-Type", "application/json")
-			.json(&Payload {
-				content: out.message.clone(),
-				..Default::default()
-			})
-	};
+		Ok(())
+	}
+}
+
+async fn request(
+	make: impl Fn() -> RequestBuilder,
+	out: &Out,
+	edit: bool,
+) -> Result<PostedMessage> {
+	// when editing, an empty attachments list removes the previous image, if any
+	let attachments = || if edit { Some(Vec::new()) } else { None };
 
-	trace!(?req, "sending request");
-	let resp = req.send().await?;
+	let resp = retry::send("discord", || {
+		let req = make();
+		Ok(if let Some(ref imagedata) = out.image {
+			assert!(out.message.is_empty(), "cannot send both text and an image");
+
+			let part = Part::bytes(imagedata.clone())
+				.file_name("image.png")
+				.mime_str("image/png")?;
+			let mut form = Form::new().part("file", part);
+			if edit {
+				form = form.text(
+					"payload_json",
+					serde_json::to_string(&Payload {
+						attachments: attachments(),
+						..Default::default()
+					})?,
+				);
+			}
+
+			req.multipart(form)
+		} else {
+			req.header("Content-Type", "application/json")
+				.json(&Payload {
+					content: out.message.clone(),
+					attachments: attachments(),
+					..Default::default()
+				})
+		})
+	})
+	.await?;
 	trace!(?resp, "response from discord");
 	let status = resp.status();
 
-	let body: HashMap<String, Value> = resp.json().await?;
-	trace!(?body, "response body");
+	let text = resp.text().await?;
+	trace!(body=%text, "response body");
+
+	if edit && is_unknown_message(status, &text) {
+		return Err(UnknownMessage.into());
+	}
 
+	let body: HashMap<String, Value> = serde_json::from_str(&text)?;
 	if !status.is_success() {
-		Err(eyre!(
+		return Err(eyre!(
 			"failed to send message to discord: {}\n{:?}",
 			status,
 			body
-		))
-	} else {
-		Ok(())
+		));
 	}
+
+	let id = body
+		.get("id")
+		.and_then(Value::as_str)
+		.ok_or_else(|| eyre!("discord did not return a message id"))?;
+
+	Ok(PostedMessage {
+		id: id.into(),
+		content: out.message.clone(),
+		image: out.image.is_some(),
+	})
 }
 
 #[derive(Clone, Debug, Default, Serialize)]
@@ -92,4 +434,134 @@ struct Payload {
 
 	#[serde(skip_serializing_if = "String::is_empty")]
 	avatar_url: String,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	attachments: Option<Vec<Value>>,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		output::OutputFormat,
+		testing::{alert, geodata, http_server_replying},
+	};
+
+	const UNKNOWN: &str = r#"{"code":10008,"message":"Unknown Message"}"#;
+
+	fn route() -> Route {
+		Route {
+			format: OutputFormat::Text,
+			..Route::default()
+		}
+	}
+
+	fn message(id: &str) -> PostedMessage {
+		PostedMessage {
+			id: id.into(),
+			content: format!("message {}", id),
+			image: false,
+		}
+	}
+
+	#[tokio::test]
+	async fn update_of_deleted_message_is_posted_afresh() {
+		let (url, requests) =
+			http_server_replying(vec![(404, UNKNOWN), (200, r#"{"id":"2"}"#)]).await;
+		let sink = DiscordSink {
+			webhook_url: format!("{}/webhooks/1/token", url),
+			stale: StaleAction::Strike,
+		};
+
+		let route = route();
+		let geo = geodata();
+		let cache = Cache::temporary().unwrap();
+		let key = sink_key(&route);
+		let original = alert();
+		cache
+			.record_messages(&key, std::slice::from_ref(&original), vec![message("1")])
+			.unwrap();
+
+		let mut update = alert();
+		update.guid = "update".into();
+		update.msg_type = MsgType::Update;
+		update.supersedes = vec![original.guid.clone()];
+		let caps = [update];
+		let out = render(caps.iter().cloned().collect(), &route, &geo)
+			.await
+			.unwrap();
+		let progress = cache.progress(&key, &caps);
+		post(&route, &sink, &cache, &geo, &caps, &out, &progress)
+			.await
+			.unwrap();
+
+		let requests = requests.await.unwrap();
+		assert_eq!(requests[0].method, "PATCH");
+		assert_eq!(requests[0].path, "/webhooks/1/token/messages/1");
+		assert_eq!(requests[1].method, "POST");
+		assert_eq!(requests[1].path, "/webhooks/1/token?wait=true");
+
+		let (batch, posted) = cache.messages_for(&key, &original.guid).unwrap().unwrap();
+		assert_eq!(batch, format!("{}/2", key));
+		assert_eq!(posted.messages[0].id, "2");
+		assert_eq!(cache.all_messages(&key).unwrap().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn edit_skips_deleted_leftover_messages() {
+		let (url, requests) =
+			http_server_replying(vec![(200, r#"{"id":"1"}"#), (404, UNKNOWN)]).await;
+		let webhook_url = format!("{}/webhooks/1/token", url);
+
+		let out = Out {
+			message: "shorter".into(),
+			image: None,
+		};
+		let messages = edit(&webhook_url, &[message("1"), message("2")], &out)
+			.await
+			.unwrap();
+		assert_eq!(messages.len(), 1);
+		assert_eq!(messages[0].id, "1");
+
+		let requests = requests.await.unwrap();
+		assert_eq!(requests[1].method, "DELETE");
+		assert_eq!(requests[1].path, "/webhooks/1/token/messages/2");
+	}
+
+	#[tokio::test]
+	async fn sweep_carries_on_past_a_failed_batch() {
+		let (url, requests) = http_server_replying(vec![
+			(400, r#"{"code":50035,"message":"Invalid Form Body"}"#),
+			(200, r#"{"id":"2"}"#),
+		])
+		.await;
+		let sink = DiscordSink {
+			webhook_url: format!("{}/webhooks/1/token", url),
+			stale: StaleAction::Strike,
+		};
+
+		let route = route();
+		let cache = Cache::temporary().unwrap();
+		let key = sink_key(&route);
+		// the fixture alert expired long ago
+		let mut other = alert();
+		other.guid = "other".into();
+		cache
+			.record_messages(&key, &[alert()], vec![message("1")])
+			.unwrap();
+		cache
+			.record_messages(&key, &[other], vec![message("2")])
+			.unwrap();
+
+		let err = sweep(&route, &sink, &cache).await.unwrap_err();
+		assert!(err.to_string().contains(&format!("{}/1", key)));
+
+		let requests = requests.await.unwrap();
+		assert_eq!(requests.len(), 2);
+		assert_eq!(requests[1].path, "/webhooks/1/token/messages/2");
+
+		let left = cache.all_messages(&key).unwrap();
+		assert_eq!(left.len(), 1);
+		assert_eq!(left[0].0, format!("{}/1", key));
+	}
 }
@@ -0,0 +1,189 @@
+use std::str::FromStr;
+
+use color_eyre::eyre::Result;
+use itertools::Itertools;
+use lettre::{
+	message::{header::ContentType, Attachment, Mailbox, MultiPart, SinglePart},
+	transport::smtp::authentication::Credentials,
+	AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tracing::{debug, trace};
+
+use crate::{
+	cap::Cap,
+	config::EmailSink,
+	output::{colour_hex, headline, markup_to_html, when_where, Out},
+};
+
+/// How to secure the connection to the SMTP server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtpSecurity {
+	/// Plain connection, e.g. for a local catcher.
+	None,
+
+	/// Upgrade a plain connection with STARTTLS.
+	StartTls,
+
+	/// Connect with TLS from the start.
+	Tls,
+}
+
+impl FromStr for SmtpSecurity {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_lowercase().as_str() {
+			"none" => Ok(Self::None),
+			"starttls" => Ok(Self::StartTls),
+			"tls" => Ok(Self::Tls),
+			_ => Err(format!("unknown smtp security: {}", s)),
+		}
+	}
+}
+
+/// Send alerts as a plain text and HTML email, with the map as an inline image.
+pub async fn send(sink: &EmailSink, caps: &[Cap], out: &Out) -> Result<()> {
+	let related =
+		MultiPart::related().singlepart(SinglePart::html(html(caps, out.image.is_some())));
+	let related = if let Some(ref image) = out.image {
+		related.singlepart(
+			Attachment::new_inline("map".into())
+				.body(image.clone(), ContentType::parse("image/png")?),
+		)
+	} else {
+		related
+	};
+
+	let mut builder = Message::builder()
+		.from(sink.from.parse::<Mailbox>()?)
+		.subject(caps.iter().map(headline).unique().join(", "));
+	for to in &sink.to {
+		builder = builder.to(to.parse::<Mailbox>()?);
+	}
+
+	let email = builder.multipart(
+		MultiPart::alternative()
+			.singlepart(SinglePart::plain(out.message.clone()))
+			.multipart(related),
+	)?;
+
+	let mailer = match sink.security {
+		SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&sink.host),
+		SmtpSecurity::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&sink.host)?,
+		SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&sink.host)?,
+	}
+	.port(sink.port);
+	let mailer = if let (Some(username), Some(password)) = (&sink.username, &sink.password) {
+		mailer.credentials(Credentials::new(username.clone(), password.clone()))
+	} else {
+		mailer
+	}
+	.build();
+
+	debug!(host=%sink.host, port=%sink.port, to=?sink.to, "sending email");
+	let resp = mailer.send(email).await?;
+	trace!(?resp, "response from smtp server");
+
+	Ok(())
+}
+
+fn html(caps: &[Cap], with_map: bool) -> String {
+	let mut html = String::from(r#"<div style="font-family: sans-serif; max-width: 40em">"#);
+
+	if with_map {
+		html.push_str(
+			r#"<p><img src="cid:map" alt="Map of alert areas" style="max-width: 100%"></p>"#,
+		);
+	}
+
+	for cap in caps {
+		// reminders have no description
+		let description = match cap.info().description.as_str() {
+			"" => String::new(),
+			description => format!("<p>{}</p>", markup_to_html(description)),
+		};
+
+		html.push_str(&format!(
+			r#"<div style="border-left: 6px solid {colour}; padding: 0.5em 1em; margin: 1em 0">
+				<h2 style="margin: 0 0 0.5em">{headline}</h2>
+				<p>{when_where}</p>
+				{description}
+			</div>"#,
+			colour = colour_hex(cap),
+			headline = markup_to_html(&headline(cap)),
+			when_where = markup_to_html(&when_where(cap)),
+			description = description,
+		));
+	}
+
+	html.push_str("</div>");
+	html
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		cap::Reminder,
+		testing::{alert, smtp_server},
+	};
+
+	#[tokio::test]
+	async fn sends_multipart_message() {
+		let (port, session) = smtp_server().await;
+		let sink = EmailSink {
+			host: "127.0.0.1".into(),
+			port,
+			security: SmtpSecurity::None,
+			username: None,
+			password: None,
+			from: "Alerts <alerts@example.com>".into(),
+			to: vec!["ops@example.com".into(), "oncall@example.com".into()],
+		};
+
+		let cap = alert();
+		let out = Out {
+			message: "plain text version".into(),
+			image: Some(b"fake png".to_vec()),
+		};
+		send(&sink, std::slice::from_ref(&cap), &out).await.unwrap();
+
+		let session = session.await.unwrap();
+		assert!(session[0].starts_with("EHLO "));
+		assert!(session[1].starts_with("MAIL FROM:<alerts@example.com>"));
+		assert!(session[2].starts_with("RCPT TO:<ops@example.com>"));
+		assert!(session[3].starts_with("RCPT TO:<oncall@example.com>"));
+		assert_eq!(session[4], "DATA");
+		assert_eq!(session.last().map(String::as_str), Some("QUIT"));
+
+		let data = &session[5];
+		assert!(data.contains("From: Alerts <alerts@example.com>\r\n"));
+		assert!(data.contains("To: ops@example.com, oncall@example.com\r\n"));
+		assert!(data.contains(&format!("Subject: {}\r\n", headline(&cap))));
+		assert!(data.contains("Content-Type: multipart/alternative;"));
+		assert!(data.contains("Content-Type: multipart/related;"));
+		assert!(data.contains("Content-Type: text/plain; charset=utf-8"));
+		assert!(data.contains("plain text version"));
+		assert!(data.contains("Content-Type: text/html; charset=utf-8"));
+		assert!(data.contains("Content-ID: <map>"));
+		assert!(data.contains("Content-Type: image/png"));
+	}
+
+	#[test]
+	fn html_references_inline_map() {
+		let cap = alert();
+		let with = html(std::slice::from_ref(&cap), true);
+		assert!(with.contains(r#"src="cid:map""#));
+		assert!(with.contains(colour_hex(&cap)));
+		assert!(!html(&[cap], false).contains("cid:map"));
+	}
+
+	#[test]
+	fn reminder_leaves_out_description() {
+		let cap = alert();
+		let reminder = html(&[cap.reminder(Reminder::Expiry)], false);
+		assert!(reminder.contains("EXPIRED: HEAVY RAIN WARNING"));
+		assert!(!reminder.contains(&cap.info().description));
+		assert!(!reminder.contains("<p></p>"));
+	}
+}
@@ -9,19 +9,23 @@ use serde::Serialize;
 use serde_json::Value;
 use tracing::trace;
 
-use crate::output::{split_long_message, Out};
+use crate::{
+	cache::Progress,
+	output::{split_long_message, Out},
+	retry,
+};
 
-pub async fn send(token: &str, thread_id: &str, out: &Out) -> Result<()> {
+pub async fn send(token: &str, thread_id: &str, out: &Out, progress: &Progress) -> Result<()> {
+	let mut parts = Vec::new();
 	let mut out = if let Out {
 		message,
 		image: Some(img),
 	} = out
 	{
-		let image_only = Out {
+		parts.push(Out {
 			message: "".into(),
 			image: Some(img.clone()),
-		};
-		send_message(token, thread_id, &image_only).await?;
+		});
 		Out {
 			message: message.clone(),
 			image: None,
@@ -32,51 +36,59 @@ pub async fn send(token: &str, thread_id: &str, out: &Out) -> Result<()> {
 
 	loop {
 		let (first, rest) = split_long_message(out, 2000, 280);
-		send_message(token, thread_id, &first).await?;
+		parts.push(first);
 		out = match rest {
 			Some(o) => o,
 			None => break,
 		};
 	}
 
+	for (i, part) in parts.iter().enumerate() {
+		if progress.sent(i, part)?.is_none() {
+			let id = send_message(token, thread_id, part).await?;
+			progress.record(i, part, &id)?;
+		}
+	}
+
 	Ok(())
 }
 
-async fn send_message(token: &str, thread_id: &str, out: &Out) -> Result<()> {
+/// Send one message, returning its ID.
+async fn send_message(token: &str, thread_id: &str, out: &Out) -> Result<String> {
 	let client = Client::new();
-	let req = client
-		.post("https://graph.facebook.com/me/messages")
-		.bearer_auth(token);
-
-	let req = if let Some(ref imagedata) = out.image {
-		assert!(out.message.is_empty(), "cannot send both text and an image");
-
-		let part = Part::bytes(imagedata.clone())
-			.file_name("image.png")
-			.mime_str("image/png")?;
-		let form = Form::new()
-			.text(
-				"recipient",
-				serde_json::to_string(&Recipient {
-					thread_key: thread_id.into(),
-				})?,
-			)
-			.text("message", serde_json::to_string(&Message::image())?)
-			.part("filedata", part);
-
-		req.multipart(form)
-	} else {
-		req.header("Content-Type", "application/json")
-			.json(&MessageData {
-				message: Message::text(&out.message),
-				recipient: Recipient {
-					thread_key: thread_id.into(),
-				},
-			})
-	};
-
-	trace!(?req, "sending request");
-	let resp = req.send().await?;
+	let resp = retry::send("facebook", || {
+		let req = client
+			.post("https://graph.facebook.com/me/messages")
+			.bearer_auth(token);
+
+		Ok(if let Some(ref imagedata) = out.image {
+			assert!(out.message.is_empty(), "cannot send both text and an image");
+
+			let part = Part::bytes(imagedata.clone())
+				.file_name("image.png")
+				.mime_str("image/png")?;
+			let form = Form::new()
+				.text(
+					"recipient",
+					serde_json::to_string(&Recipient {
+						thread_key: thread_id.into(),
+					})?,
+				)
+				.text("message", serde_json::to_string(&Message::image())?)
+				.part("filedata", part);
+
+			req.multipart(form)
+		} else {
+			req.header("Content-Type", "application/json")
+				.json(&MessageData {
+					message: Message::text(&out.message),
+					recipient: Recipient {
+						thread_key: thread_id.into(),
+					},
+				})
+		})
+	})
+	.await?;
 	trace!(?resp, "response from facebook");
 	let status = resp.status();
 
@@ -90,7 +102,11 @@ async fn send_message(token: &str, thread_id: &str, out: &Out) -> Result<()> {
 			body
 		))
 	} else {
-		Ok(())
+		Ok(body
+			.get("message_id")
+			.and_then(Value::as_str)
+			.map(String::from)
+			.unwrap_or_default())
 	}
 }
 
@@ -136,18 +152,13 @@ struct Attachment {
 	payload: Payload,
 }
 
-#[derive(Clone, Copy, Debug, Serialize)]
+#[derive(Clone, Copy, Debug, Default, Serialize)]
 #[serde(rename_all = "lowercase")]
 enum AttachmentType {
+	#[default]
 	Image,
 }
 
-impl Default for AttachmentType {
-	fn default() -> Self {
-		Self::Image
-	}
-}
-
 #[derive(Clone, Debug, Default, Serialize)]
 struct Payload {
 	is_reusable: bool,
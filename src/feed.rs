@@ -1,14 +1,40 @@
-use color_eyre::eyre::Result;
-use futures::future::try_join_all;
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Report, Result};
+use futures::future::join_all;
 use itertools::Itertools;
 use mime::Mime;
+use reqwest::header::{HeaderMap, HeaderName, AGE, CACHE_CONTROL, DATE, EXPIRES};
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+	cache::{Cache, FailedItem, ItemState},
+	cap::{fetch_cap, Cap},
+	signature::Verifier,
+};
 
-use sled::Tree;
-use tracing::{debug, info, trace};
+#[derive(Clone, Debug, Default)]
+pub struct Feed {
+	pub caps: Vec<Cap>,
 
-use crate::cap::{fetch_cap, Cap};
+	/// How long the feed response may be cached for, from `Cache-Control` or `Expires`.
+	pub ttl: Option<Duration>,
+
+	/// Items whose CAP couldn't be fetched or parsed.
+	pub failed: Vec<FailedItem>,
+}
 
-pub async fn fetch_feed(cache: Tree, url: String) -> Result<Vec<Cap>> {
+/// Fetch a feed and the CAPs of its new items.
+///
+/// With `retry`, items that were seen before but never delivered are fetched again too.
+/// With a `verifier`, CAPs' signatures are checked as they're fetched.
+pub async fn fetch_feed(
+	cache: Cache,
+	url: String,
+	retry: bool,
+	verifier: Option<Arc<Verifier>>,
+) -> Result<Feed> {
 	info!(%url, "fetching CAP feed");
 	let resp = reqwest::get(&url).await?.error_for_status()?;
 
@@ -28,6 +54,9 @@ pub async fn fetch_feed(cache: Tree, url: String) -> Result<Vec<Cap>> {
 	);
 	trace!(status=?resp.status(), headers=?resp.headers(), "headers");
 
+	let ttl = cache_ttl(resp.headers());
+	debug!(%url, ?ttl, "feed cache lifetime");
+
 	let media_type: Mime = content_type.parse()?;
 	trace!(%url, ?media_type, "decoded media type");
 
@@ -66,18 +95,21 @@ pub async fn fetch_feed(cache: Tree, url: String) -> Result<Vec<Cap>> {
 	for item in items {
 		trace!(%url, guid=%item.guid, "checking item against cache");
 
-		if cache
-			.compare_and_swap(
-				item.guid.as_bytes().to_owned(),
-				None::<Vec<u8>>,
-				Some(item.link.as_bytes().to_owned()),
-			)?
-			.is_err()
-		{
-			trace!(%url, guid=%item.guid, "item already in cache, skipping");
-		} else {
+		if cache.discover(&url, &item, retry)? {
 			trace!(%url, guid=%item.guid, "item wasn't in cache, keeping");
 			new.push(item);
+		} else {
+			trace!(%url, guid=%item.guid, "item already in cache, skipping");
+		}
+	}
+
+	if retry {
+		// items that have since dropped off the feed
+		for item in cache.stuck_items(&url)? {
+			if !new.contains(&item) && cache.retry(&item)? {
+				debug!(%url, guid=%item.guid, "retrying item that's no longer in the feed");
+				new.push(item);
+			}
 		}
 	}
 
@@ -85,15 +117,76 @@ pub async fn fetch_feed(cache: Tree, url: String) -> Result<Vec<Cap>> {
 	trace!(%url, ?new, "new items");
 
 	debug!(%url, "fetching CAPs for new items");
-	let caps = try_join_all(
-		new.into_iter()
-			.map(move |item| tokio::spawn(async move { fetch_cap(item).await })),
+	let results = join_all(
+		new.iter()
+			.cloned()
+			.map(|item| tokio::spawn(fetch_cap(item, verifier.clone()))),
 	)
-	.await?
-	.into_iter()
-	.collect::<Result<_, _>>()?;
+	.await;
 
-	Ok(caps)
+	// one bad CAP shouldn't hold up the rest
+	let mut caps = Vec::with_capacity(new.len());
+	let mut failed = Vec::new();
+	for (item, result) in new.into_iter().zip(results) {
+		match result.map_err(Report::from).and_then(|cap| cap) {
+			Ok(cap) => {
+				cache.set_item_state(&item.guid, ItemState::Fetched)?;
+				cache.clear_failure(&item.guid)?;
+				caps.push(cap);
+			}
+			Err(err) => {
+				warn!(%url, guid=%item.guid, link=%item.link, "failed to fetch or parse CAP: {:?}", err);
+				failed.push(cache.record_failure(&url, &item, format!("{:#}", err))?);
+			}
+		}
+	}
+
+	if !failed.is_empty() {
+		warn!(%url, "{} of {} CAPs failed", failed.len(), failed.len() + caps.len());
+	}
+
+	Ok(Feed { caps, ttl, failed })
+}
+
+fn cache_ttl(headers: &HeaderMap) -> Option<Duration> {
+	let header = move |name: HeaderName| headers.get(name).and_then(|v| v.to_str().ok());
+
+	if let Some(cc) = header(CACHE_CONTROL) {
+		let directives = cc.split(',').map(str::trim).collect::<Vec<_>>();
+
+		// these win over a max-age, wherever they are
+		if directives
+			.iter()
+			.any(|d| d.eq_ignore_ascii_case("no-cache") || d.eq_ignore_ascii_case("no-store"))
+		{
+			return Some(Duration::ZERO);
+		}
+
+		for directive in directives {
+			if let Some((name, value)) = directive.split_once('=') {
+				if name.trim().eq_ignore_ascii_case("max-age") {
+					if let Ok(max_age) = value.trim().trim_matches('"').parse::<u64>() {
+						let age = header(AGE)
+							.and_then(|v| v.trim().parse::<u64>().ok())
+							.unwrap_or(0);
+						return Some(Duration::from_secs(max_age.saturating_sub(age)));
+					}
+				}
+			}
+		}
+	}
+
+	let expires = DateTime::parse_from_rfc2822(header(EXPIRES)?).ok()?;
+	let now = header(DATE)
+		.and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+		.map_or_else(Utc::now, |d| d.with_timezone(&Utc));
+	Some(
+		expires
+			.with_timezone(&Utc)
+			.signed_duration_since(now)
+			.to_std()
+			.unwrap_or(Duration::ZERO),
+	)
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -101,3 +194,140 @@ pub struct Item {
 	pub guid: String,
 	pub link: String,
 }
+
+#[cfg(test)]
+mod tests {
+	use reqwest::header::HeaderValue;
+
+	use super::*;
+
+	fn ttl(headers: &[(HeaderName, &str)]) -> Option<Duration> {
+		let mut map = HeaderMap::new();
+		for (name, value) in headers {
+			map.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+		}
+		cache_ttl(&map)
+	}
+
+	const DATE_VALUE: &str = "Sat, 17 Oct 2026 10:00:00 GMT";
+
+	#[test]
+	fn max_age() {
+		assert_eq!(
+			ttl(&[(CACHE_CONTROL, "public, max-age=300")]),
+			Some(Duration::from_secs(300))
+		);
+		assert_eq!(
+			ttl(&[(CACHE_CONTROL, "Max-Age = \"60\"")]),
+			Some(Duration::from_secs(60))
+		);
+	}
+
+	#[test]
+	fn max_age_less_age() {
+		assert_eq!(
+			ttl(&[(CACHE_CONTROL, "max-age=300"), (AGE, "100")]),
+			Some(Duration::from_secs(200))
+		);
+		assert_eq!(
+			ttl(&[(CACHE_CONTROL, "max-age=300"), (AGE, "400")]),
+			Some(Duration::ZERO)
+		);
+	}
+
+	#[test]
+	fn no_cache_and_no_store() {
+		assert_eq!(ttl(&[(CACHE_CONTROL, "no-cache")]), Some(Duration::ZERO));
+		assert_eq!(
+			ttl(&[(CACHE_CONTROL, "max-age=60, No-Store")]),
+			Some(Duration::ZERO)
+		);
+		assert_eq!(
+			ttl(&[
+				(CACHE_CONTROL, "no-cache"),
+				(DATE, DATE_VALUE),
+				(EXPIRES, "Sat, 17 Oct 2026 11:00:00 GMT"),
+			]),
+			Some(Duration::ZERO)
+		);
+	}
+
+	#[test]
+	fn max_age_wins_over_expires() {
+		assert_eq!(
+			ttl(&[
+				(CACHE_CONTROL, "max-age=30"),
+				(DATE, DATE_VALUE),
+				(EXPIRES, "Sat, 17 Oct 2026 11:00:00 GMT"),
+			]),
+			Some(Duration::from_secs(30))
+		);
+	}
+
+	#[test]
+	fn expires_in_the_future() {
+		assert_eq!(
+			ttl(&[
+				(DATE, DATE_VALUE),
+				(EXPIRES, "Sat, 17 Oct 2026 10:05:00 GMT")
+			]),
+			Some(Duration::from_secs(300))
+		);
+		assert_eq!(
+			ttl(&[
+				(DATE, DATE_VALUE),
+				(EXPIRES, "Sat, 17 Oct 2026 23:05:00 +1300")
+			]),
+			Some(Duration::from_secs(300))
+		);
+	}
+
+	#[test]
+	fn expires_in_the_past() {
+		assert_eq!(
+			ttl(&[
+				(DATE, DATE_VALUE),
+				(EXPIRES, "Sat, 17 Oct 2026 09:00:00 GMT")
+			]),
+			Some(Duration::ZERO)
+		);
+
+		// without a date, it's compared to the clock
+		assert_eq!(
+			ttl(&[(EXPIRES, "Thu, 01 Jan 1970 00:00:00 GMT")]),
+			Some(Duration::ZERO)
+		);
+	}
+
+	#[test]
+	fn malformed_headers() {
+		assert_eq!(ttl(&[]), None);
+		assert_eq!(ttl(&[(CACHE_CONTROL, "max-age=soon")]), None);
+		assert_eq!(ttl(&[(CACHE_CONTROL, "max-age")]), None);
+		assert_eq!(ttl(&[(EXPIRES, "0")]), None);
+		assert_eq!(ttl(&[(EXPIRES, "tomorrow")]), None);
+
+		// a bad max-age falls back to the expiry, and a bad age is ignored
+		assert_eq!(
+			ttl(&[
+				(CACHE_CONTROL, "max-age=-1"),
+				(DATE, DATE_VALUE),
+				(EXPIRES, "Sat, 17 Oct 2026 10:01:00 GMT"),
+			]),
+			Some(Duration::from_secs(60))
+		);
+		assert_eq!(
+			ttl(&[(CACHE_CONTROL, "max-age=60"), (AGE, "old")]),
+			Some(Duration::from_secs(60))
+		);
+
+		// a bad date is replaced by the clock
+		assert_eq!(
+			ttl(&[
+				(DATE, "yesterday"),
+				(EXPIRES, "Thu, 01 Jan 1970 00:00:00 GMT")
+			]),
+			Some(Duration::ZERO)
+		);
+	}
+}
@@ -0,0 +1,722 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use crate::cap::Cap;
+
+const SEVERITY: &[&str] = &["unknown", "minor", "moderate", "severe", "extreme"];
+const URGENCY: &[&str] = &["unknown", "past", "future", "expected", "immediate"];
+const CERTAINTY: &[&str] = &["unknown", "unlikely", "possible", "likely", "observed"];
+
+/// An expression to select alerts by their fields, like
+/// `urgency in [Immediate, Expected] and certainty != Unlikely and event ~ "Thunderstorm"`.
+///
+/// Comparisons are of a field with a value, with `=`, `!=`, `~` (contains), `!~`, `in [a, b]`,
+/// `not in [a, b]`, and `<`, `<=`, `>`, `>=` for severity, urgency, certainty, and numbers.
+/// They're combined with `and`, `or`, `not`, and parentheses. Values can be quoted, and are
+/// compared without regard to case.
+///
+/// Fields are those of the alert (`status`, `scope`, `msg_type`, `sender`, `source`) and of its
+/// info block (`category`, `event`, `urgency`, `severity`, `certainty`, `sender_name`, `language`,
+/// `headline`, `audience`, `response_type`, `area`), and its parameters and event codes as
+/// `parameter.NAME` and `event_code.NAME`. Other names are rejected, to catch typos. Fields with
+/// several values (`category`, `response_type`, `area`) match when any of their values does.
+#[derive(Clone, Debug)]
+pub struct Filter {
+	source: String,
+	expr: Expr,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+	And(Box<Expr>, Box<Expr>),
+	Or(Box<Expr>, Box<Expr>),
+	Not(Box<Expr>),
+	Compare {
+		field: String,
+		op: Op,
+		values: Vec<String>,
+	},
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+	Eq,
+	Ne,
+	Contains,
+	NotContains,
+	In,
+	NotIn,
+	Lt,
+	Le,
+	Gt,
+	Ge,
+}
+
+impl Filter {
+	/// Parse a filter, with the position of the problem if it's invalid.
+	pub fn parse(s: &str) -> Result<Self, ParseError> {
+		let error = |(offset, message): (usize, String)| ParseError {
+			column: s[..offset].chars().count() + 1,
+			message,
+			filter: s.into(),
+		};
+
+		let mut parser = Parser {
+			tokens: tokenize(s).map_err(error)?,
+			pos: 0,
+			end: s.len(),
+		};
+
+		let expr = parser.or().map_err(error)?;
+		if let Some((offset, token)) = parser.tokens.get(parser.pos) {
+			return Err(error((*offset, format!("unexpected {}", token))));
+		}
+
+		Ok(Self {
+			source: s.into(),
+			expr,
+		})
+	}
+
+	pub fn matches(&self, cap: &Cap) -> bool {
+		self.expr.eval(cap)
+	}
+}
+
+impl fmt::Display for Filter {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.source)
+	}
+}
+
+impl FromStr for Filter {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Self::parse(s).map_err(|err| err.to_string())
+	}
+}
+
+/// Why a filter couldn't be parsed, and where.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+	/// Column of the problem in the filter, counting characters from 1.
+	pub column: usize,
+	pub message: String,
+	filter: String,
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{} at column {} of filter: {}",
+			self.message, self.column, self.filter
+		)
+	}
+}
+
+impl Expr {
+	fn eval(&self, cap: &Cap) -> bool {
+		match self {
+			Self::And(a, b) => a.eval(cap) && b.eval(cap),
+			Self::Or(a, b) => a.eval(cap) || b.eval(cap),
+			Self::Not(a) => !a.eval(cap),
+			Self::Compare { field, op, values } => {
+				let actual = field_values(cap, field);
+				let eq = |a: &str| values.iter().any(|v| a.eq_ignore_ascii_case(v));
+				let contains = |a: &str| {
+					let a = a.to_lowercase();
+					values.iter().any(|v| a.contains(&v.to_lowercase()))
+				};
+				let cmp = |a: &str| {
+					values
+						.iter()
+						.any(|v| match (rank(field, a), rank(field, v)) {
+							(Some(a), Some(v)) => match op {
+								Op::Lt => a < v,
+								Op::Le => a <= v,
+								Op::Gt => a > v,
+								Op::Ge => a >= v,
+								_ => false,
+							},
+							_ => false,
+						})
+				};
+
+				match op {
+					Op::Eq | Op::In => actual.iter().any(|a| eq(a)),
+					Op::Ne | Op::NotIn => !actual.iter().any(|a| eq(a)),
+					Op::Contains => actual.iter().any(|a| contains(a)),
+					Op::NotContains => !actual.iter().any(|a| contains(a)),
+					Op::Lt | Op::Le | Op::Gt | Op::Ge => actual.iter().any(|a| cmp(a)),
+				}
+			}
+		}
+	}
+}
+
+/// All the values of a field in an alert: none if it doesn't have it, several for e.g. areas.
+fn field_values(cap: &Cap, field: &str) -> Vec<String> {
+	let info = cap.info();
+	let one = |value: &str| {
+		if value.is_empty() {
+			Vec::new()
+		} else {
+			vec![value.to_string()]
+		}
+	};
+
+	match normalise(field).as_str() {
+		"status" => one(&cap.status),
+		"scope" => one(&cap.scope),
+		"msgtype" => vec![format!("{:?}", cap.msg_type)],
+		"sender" => one(&cap.sender),
+		"source" => cap.source.iter().cloned().collect(),
+		"category" => info.categories.clone(),
+		"event" => one(&info.event),
+		"urgency" => one(&info.urgency),
+		"severity" => vec![format!("{:?}", info.severity)],
+		"certainty" => one(&info.certainty),
+		"sendername" => one(&info.sender_name),
+		"language" => one(&info.language),
+		"headline" => one(&info.headline),
+		"audience" => info.audience.iter().cloned().collect(),
+		"responsetype" => info.response_types.clone(),
+		"area" => info.areas.iter().map(|a| a.desc.clone()).collect(),
+		_ => match field.split_once('.') {
+			Some((prefix, name)) if normalise(prefix) == "eventcode" => {
+				lookup(&info.event_codes, name)
+			}
+			Some((prefix, name)) if normalise(prefix) == "parameter" => {
+				lookup(&info.parameters, name)
+			}
+			_ => Vec::new(),
+		},
+	}
+}
+
+/// Whether a field name is one [`field_values`] knows, so typos are caught when parsing.
+fn is_field(field: &str) -> bool {
+	const FIELDS: &[&str] = &[
+		"status",
+		"scope",
+		"msgtype",
+		"sender",
+		"source",
+		"category",
+		"event",
+		"urgency",
+		"severity",
+		"certainty",
+		"sendername",
+		"language",
+		"headline",
+		"audience",
+		"responsetype",
+		"area",
+	];
+
+	match field.split_once('.') {
+		Some((prefix, name)) => {
+			matches!(normalise(prefix).as_str(), "eventcode" | "parameter") && !name.is_empty()
+		}
+		None => FIELDS.contains(&normalise(field).as_str()),
+	}
+}
+
+fn lookup(map: &HashMap<String, String>, name: &str) -> Vec<String> {
+	map.iter()
+		.find(|(k, _)| k.eq_ignore_ascii_case(name))
+		.map(|(_, v)| v.clone())
+		.into_iter()
+		.collect()
+}
+
+/// Lowercase without underscores, so `msg_type`, `msgType`, and `MsgType` are all the same field.
+fn normalise(field: &str) -> String {
+	field.to_lowercase().replace('_', "")
+}
+
+/// Scale for fields with ordered values, if the field is one of those.
+fn scale(field: &str) -> Option<&'static [&'static str]> {
+	match normalise(field).as_str() {
+		"severity" => Some(SEVERITY),
+		"urgency" => Some(URGENCY),
+		"certainty" => Some(CERTAINTY),
+		_ => None,
+	}
+}
+
+/// Position of a value on its field's scale, or as a number for other fields.
+fn rank(field: &str, value: &str) -> Option<f64> {
+	match scale(field) {
+		Some(scale) => scale
+			.iter()
+			.position(|s| s.eq_ignore_ascii_case(value))
+			.map(|n| n as f64),
+		None => value.trim().parse().ok(),
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+	Open,
+	Close,
+	OpenList,
+	CloseList,
+	Comma,
+	Op(&'static str),
+	Word(String),
+	Quoted(String),
+}
+
+impl fmt::Display for Token {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Open => write!(f, "`(`"),
+			Self::Close => write!(f, "`)`"),
+			Self::OpenList => write!(f, "`[`"),
+			Self::CloseList => write!(f, "`]`"),
+			Self::Comma => write!(f, "`,`"),
+			Self::Op(op) => write!(f, "`{}`", op),
+			Self::Word(word) => write!(f, "`{}`", word),
+			Self::Quoted(s) => write!(f, "{:?}", s),
+		}
+	}
+}
+
+/// A parse error, at a byte offset into the filter.
+type Error = (usize, String);
+
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, Error> {
+	const OPS: &[&str] = &["==", "!=", "!~", "<=", ">=", "=", "~", "<", ">"];
+
+	let mut tokens = Vec::new();
+	let mut rest = s.trim_start();
+	while let Some(c) = rest.chars().next() {
+		let offset = s.len() - rest.len();
+		let single = match c {
+			'(' => Some(Token::Open),
+			')' => Some(Token::Close),
+			'[' => Some(Token::OpenList),
+			']' => Some(Token::CloseList),
+			',' => Some(Token::Comma),
+			_ => None,
+		};
+
+		if let Some(token) = single {
+			tokens.push((offset, token));
+			rest = &rest[1..];
+		} else if let Some(op) = OPS.iter().copied().find(|op| rest.starts_with(*op)) {
+			tokens.push((offset, Token::Op(op)));
+			rest = &rest[op.len()..];
+		} else if c == '"' || c == '\'' {
+			let mut quoted = String::new();
+			let mut chars = rest[1..].char_indices();
+			let end = loop {
+				match chars.next() {
+					Some((_, '\\')) => match chars.next() {
+						Some((_, escaped)) => quoted.push(escaped),
+						None => break None,
+					},
+					Some((n, q)) if q == c => break Some(n + 2),
+					Some((_, other)) => quoted.push(other),
+					None => break None,
+				}
+			}
+			.ok_or_else(|| (offset, "unterminated string".to_string()))?;
+
+			tokens.push((offset, Token::Quoted(quoted)));
+			rest = &rest[end..];
+		} else {
+			let end = rest
+				.find(|c: char| c.is_whitespace() || "()[],\"'=!~<>".contains(c))
+				.unwrap_or(rest.len());
+			if end == 0 {
+				return Err((offset, format!("unexpected `{}`", c)));
+			}
+
+			tokens.push((offset, Token::Word(rest[..end].into())));
+			rest = &rest[end..];
+		}
+
+		rest = rest.trim_start();
+	}
+
+	Ok(tokens)
+}
+
+struct Parser {
+	tokens: Vec<(usize, Token)>,
+	pos: usize,
+
+	/// Offset of the end of the filter, for errors about it ending too soon.
+	end: usize,
+}
+
+impl Parser {
+	fn peek(&self) -> Option<&Token> {
+		self.tokens.get(self.pos).map(|(_, token)| token)
+	}
+
+	/// The next token and its offset, or the end of the filter.
+	fn next(&mut self) -> (usize, Option<Token>) {
+		let next = match self.tokens.get(self.pos) {
+			Some((offset, token)) => (*offset, Some(token.clone())),
+			None => (self.end, None),
+		};
+		self.pos += 1;
+		next
+	}
+
+	/// Consume the next token if it's that keyword.
+	fn keyword(&mut self, word: &str) -> bool {
+		match self.peek() {
+			Some(Token::Word(w)) if w.eq_ignore_ascii_case(word) => {
+				self.pos += 1;
+				true
+			}
+			_ => false,
+		}
+	}
+
+	fn or(&mut self) -> Result<Expr, Error> {
+		let mut expr = self.and()?;
+		while self.keyword("or") {
+			expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+		}
+		Ok(expr)
+	}
+
+	fn and(&mut self) -> Result<Expr, Error> {
+		let mut expr = self.not()?;
+		while self.keyword("and") {
+			expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+		}
+		Ok(expr)
+	}
+
+	fn not(&mut self) -> Result<Expr, Error> {
+		if self.keyword("not") {
+			Ok(Expr::Not(Box::new(self.not()?)))
+		} else {
+			self.atom()
+		}
+	}
+
+	fn atom(&mut self) -> Result<Expr, Error> {
+		let (offset, field) = match self.next() {
+			(_, Some(Token::Open)) => {
+				let expr = self.or()?;
+				return match self.next() {
+					(_, Some(Token::Close)) => Ok(expr),
+					(offset, found) => {
+						Err((offset, format!("expected `)`, found {}", or_end(found))))
+					}
+				};
+			}
+			(offset, Some(Token::Word(field))) => (offset, field),
+			(offset, found) => {
+				return Err((
+					offset,
+					format!("expected a field name, found {}", or_end(found)),
+				))
+			}
+		};
+
+		if !is_field(&field) {
+			return Err((
+				offset,
+				format!(
+					"unknown field `{}` (parameters are `parameter.NAME`, event codes `event_code.NAME`)",
+					field
+				),
+			));
+		}
+
+		let op = match self.next() {
+			(_, Some(Token::Op("="))) | (_, Some(Token::Op("=="))) => Op::Eq,
+			(_, Some(Token::Op("!="))) => Op::Ne,
+			(_, Some(Token::Op("~"))) => Op::Contains,
+			(_, Some(Token::Op("!~"))) => Op::NotContains,
+			(_, Some(Token::Op("<"))) => Op::Lt,
+			(_, Some(Token::Op("<="))) => Op::Le,
+			(_, Some(Token::Op(">"))) => Op::Gt,
+			(_, Some(Token::Op(">="))) => Op::Ge,
+			(_, Some(Token::Word(w))) if w.eq_ignore_ascii_case("in") => Op::In,
+			(_, Some(Token::Word(w))) if w.eq_ignore_ascii_case("not") && self.keyword("in") => {
+				Op::NotIn
+			}
+			(offset, found) => {
+				return Err((
+					offset,
+					format!(
+						"expected a comparison after `{}`, found {}",
+						field,
+						or_end(found)
+					),
+				))
+			}
+		};
+
+		let values = if matches!(op, Op::In | Op::NotIn) {
+			self.list()?
+		} else {
+			vec![self.value()?]
+		};
+
+		// catch typos like `severity >= Sever`, which would never match
+		for (offset, value) in &values {
+			if let Some(scale) = scale(&field) {
+				if !scale.iter().any(|s| s.eq_ignore_ascii_case(value)) {
+					return Err((*offset, format!("invalid {}: {}", field, value)));
+				}
+			} else if matches!(op, Op::Lt | Op::Le | Op::Gt | Op::Ge)
+				&& value.trim().parse::<f64>().is_err()
+			{
+				return Err((
+					*offset,
+					format!("`{}` can only be compared to numbers", field),
+				));
+			}
+		}
+
+		Ok(Expr::Compare {
+			field,
+			op,
+			values: values.into_iter().map(|(_, value)| value).collect(),
+		})
+	}
+
+	fn value(&mut self) -> Result<(usize, String), Error> {
+		match self.next() {
+			(offset, Some(Token::Word(value))) | (offset, Some(Token::Quoted(value))) => {
+				Ok((offset, value))
+			}
+			(offset, found) => Err((offset, format!("expected a value, found {}", or_end(found)))),
+		}
+	}
+
+	fn list(&mut self) -> Result<Vec<(usize, String)>, Error> {
+		match self.next() {
+			(_, Some(Token::OpenList)) => {}
+			(offset, found) => {
+				return Err((offset, format!("expected `[`, found {}", or_end(found))))
+			}
+		}
+
+		let mut values = vec![self.value()?];
+		loop {
+			match self.next() {
+				(_, Some(Token::Comma)) => values.push(self.value()?),
+				(_, Some(Token::CloseList)) => return Ok(values),
+				(offset, found) => {
+					return Err((
+						offset,
+						format!("expected `,` or `]`, found {}", or_end(found)),
+					))
+				}
+			}
+		}
+	}
+}
+
+/// Describe a token for an error, or the end of the filter if there's none.
+fn or_end(token: Option<Token>) -> String {
+	match token {
+		Some(token) => token.to_string(),
+		None => "end of filter".into(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::alert;
+
+	/// The heavy rain fixture, with a second category and a numeric parameter.
+	fn cap() -> Cap {
+		let mut cap = alert();
+		cap.infos[0].categories.push("Safety".into());
+		cap.infos[0]
+			.parameters
+			.insert("Rainfall".into(), "150".into());
+		cap
+	}
+
+	fn matches(filter: &str) -> bool {
+		Filter::parse(filter)
+			.unwrap_or_else(|err| panic!("{}", err))
+			.matches(&cap())
+	}
+
+	fn error(filter: &str) -> (usize, String) {
+		let err = Filter::parse(filter).expect_err(filter);
+		(err.column, err.message)
+	}
+
+	#[test]
+	fn and_binds_tighter_than_or() {
+		assert!(matches(
+			r#"event = "Heavy Rain" or event = Flood and severity = Minor"#
+		));
+		assert!(!matches(
+			r#"event = Flood and severity = Severe or event = Tornado"#
+		));
+	}
+
+	#[test]
+	fn not_binds_tighter_than_and() {
+		assert!(!matches(r#"not event = "Heavy Rain" and severity = Minor"#));
+		assert!(matches(r#"not event = Flood and not severity = Minor"#));
+		assert!(matches(r#"not not event = "Heavy Rain""#));
+	}
+
+	#[test]
+	fn parentheses_group() {
+		assert!(!matches(
+			r#"(event = "Heavy Rain" or event = Flood) and severity = Minor"#
+		));
+		assert!(matches(
+			r#"not (event = Flood or (severity = Minor and urgency = Expected))"#
+		));
+	}
+
+	#[test]
+	fn in_and_not_in() {
+		assert!(matches("urgency in [Immediate, Expected]"));
+		assert!(!matches("urgency not in [Immediate,Expected]"));
+		assert!(matches("certainty not in [Unlikely]"));
+		assert!(matches("area IN ['Tararua Range']"));
+	}
+
+	#[test]
+	fn several_values_match_any() {
+		assert!(matches("category = Met"));
+		assert!(matches("category = safety"));
+		assert!(matches("category in [Geo, Safety]"));
+		assert!(!matches("category != Met"));
+		assert!(!matches("category not in [Fire, Met]"));
+		assert!(matches("response_type = Prepare"));
+	}
+
+	#[test]
+	fn missing_fields() {
+		assert!(!matches("source = anything"));
+		assert!(matches("source != anything"));
+		assert!(matches("event_code.SAME !~ WX"));
+		assert!(!matches("parameter.Missing in [a, b]"));
+	}
+
+	#[test]
+	fn contains() {
+		assert!(matches("event ~ rain"));
+		assert!(matches("headline !~ Red"));
+		assert!(matches("parameter.ColourCode ~ ORANGE"));
+	}
+
+	#[test]
+	fn quoting_and_escapes() {
+		assert!(matches(r#"headline = "Heavy Rain Warning - Orange""#));
+		assert!(matches("headline = 'heavy rain warning - orange'"));
+
+		let tokens = tokenize(r#"a = "say \"hi\"" or b = 'it\'s' and c = "back\\slash""#)
+			.unwrap()
+			.into_iter()
+			.filter_map(|(_, token)| match token {
+				Token::Quoted(s) => Some(s),
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(tokens, vec![r#"say "hi""#, "it's", r"back\slash"]);
+
+		// operators and brackets are literal inside quotes
+		assert!(!matches(r#"event = "Rain) or (severity = Severe""#));
+	}
+
+	#[test]
+	fn scale_comparisons() {
+		assert!(matches("severity >= Moderate"));
+		assert!(matches("severity <= severe"));
+		assert!(!matches("severity > Severe"));
+		assert!(matches("urgency < Immediate"));
+		assert!(!matches("certainty <= Possible"));
+		assert!(matches("parameter.Rainfall > 100"));
+		assert!(!matches("parameter.Rainfall >= 150.5"));
+	}
+
+	#[test]
+	fn field_names_are_normalised() {
+		assert!(matches("msg_type = Alert"));
+		assert!(matches("msgType = alert"));
+		assert!(matches("SENDER_NAME = MetService"));
+		assert!(matches("Parameter.colourcode = Orange"));
+	}
+
+	#[test]
+	fn unknown_fields() {
+		let (column, message) = error("ColourCode = Red");
+		assert_eq!(column, 1);
+		assert!(message.starts_with("unknown field `ColourCode`"));
+
+		assert_eq!(error("severity = Severe and evnt = Flood").0, 23);
+		assert_eq!(error("parameter. = x").0, 1);
+		assert_eq!(error("event_codes.SAME = x").0, 1);
+	}
+
+	#[test]
+	fn error_positions() {
+		assert_eq!(
+			error("severity >= Sever"),
+			(13, "invalid severity: Sever".into())
+		);
+		assert_eq!(
+			error("urgency in [Expected, Soon]"),
+			(23, "invalid urgency: Soon".into())
+		);
+		assert_eq!(
+			error("parameter.Rainfall > lots"),
+			(
+				22,
+				"`parameter.Rainfall` can only be compared to numbers".into()
+			)
+		);
+		assert_eq!(
+			error("event = "),
+			(9, "expected a value, found end of filter".into())
+		);
+		assert_eq!(
+			error("(event = Flood"),
+			(15, "expected `)`, found end of filter".into())
+		);
+		assert_eq!(error("event = Flood )"), (15, "unexpected `)`".into()));
+		assert_eq!(
+			error("event Flood"),
+			(
+				7,
+				"expected a comparison after `event`, found `Flood`".into()
+			)
+		);
+		assert_eq!(
+			error("area in [a b]"),
+			(12, "expected `,` or `]`, found `b`".into())
+		);
+		assert_eq!(error("event = \"Flood"), (9, "unterminated string".into()));
+		assert_eq!(error("event = Flood & x"), (15, "unexpected `&`".into()));
+
+		// columns count characters, not bytes
+		assert_eq!(error("area = \"Tāmaki\" )").0, 17);
+	}
+
+	#[test]
+	fn error_display() {
+		assert_eq!(
+			Filter::parse("severity >= Sever").unwrap_err().to_string(),
+			"invalid severity: Sever at column 13 of filter: severity >= Sever"
+		);
+		assert_eq!(
+			"severity >= Sever".parse::<Filter>().unwrap_err(),
+			"invalid severity: Sever at column 13 of filter: severity >= Sever"
+		);
+	}
+}
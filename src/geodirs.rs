@@ -1,14 +1,408 @@
 use std::{
+	collections::HashMap,
 	convert::TryFrom,
+	iter::once,
 	path::{Path, PathBuf},
+	str::FromStr,
 };
 
+use cheap_ruler::{CheapRuler, DistanceUnit};
 use color_eyre::eyre::Result;
 use futures::future::try_join_all;
-use geo::{Geometry, GeometryCollection, MultiPolygon, Polygon};
+use geo::{
+	chamberlain_duquette_area::ChamberlainDuquetteArea,
+	concave_hull::ConcaveHull,
+	prelude::{Contains, Intersects},
+	simplify::Simplify,
+	Coordinate, Geometry, GeometryCollection, LineString, MultiPolygon, Point, Polygon,
+};
+use geo_booleanop::boolean::BooleanOp;
 use geojson::{quick_collection, GeoJson};
+use itertools::Itertools;
+use serde_json::Value;
 use tokio::{fs::File, io::AsyncReadExt};
-use tracing::{debug, trace};
+use tracing::{debug, info, trace};
+
+use crate::{
+	cap::{Cap, Geocode},
+	config::Route,
+	output::OutputFormat,
+};
+
+/// Rough length of a degree, to turn distances in kilometres into tolerances in degrees.
+///
+/// That of latitude is used, as it's the longest, so a tolerance is never more than it should be.
+const KM_PER_DEGREE: f64 = 111.3;
+
+/// Boundaries, outlines, and geocodes, loaded once and kept in memory for the lifetime of the process.
+#[derive(Clone, Debug)]
+pub struct Geodata {
+	/// Union of the boundary polygons, used to filter CAPs, grown by the route's buffer.
+	///
+	/// Boundaries can overlap, especially once grown, so they're merged to not count any area twice.
+	pub boundaries: MultiPolygon<f64>,
+
+	/// The same polygons, grouped by the boundary they're part of, to say which an alert affects.
+	pub named: Vec<Boundary>,
+
+	/// Area of the boundaries, in square metres.
+	pub boundaries_area: f64,
+
+	/// Hull of all the boundaries, used to crop and frame maps.
+	pub hull: MultiPolygon<f64>,
+
+	/// Outlines for basemaps. Only loaded for the `text+map` format.
+	pub outlines: MultiPolygon<f64>,
+
+	/// Polygons for the geocodes of CAP areas that don't come with their own.
+	pub geocodes: HashMap<Geocode, Vec<Polygon<f64>>>,
+}
+
+impl Geodata {
+	pub async fn load(route: &Route) -> Result<Self> {
+		info!(route=%route.name, "loading geojson boundaries");
+		let mut named = load_boundaries(&route.boundaries).await?;
+		if route.buffer > 0.0 && !named.is_empty() {
+			info!(route=%route.name, km=%route.buffer, "growing boundaries");
+			for boundary in &mut named {
+				boundary.polygons = buffer(&boundary.polygons, route.buffer);
+			}
+		}
+		let boundaries = merge(&named);
+		let boundaries_area = area(&boundaries);
+		debug!(route=%route.name, km2=%(boundaries_area / 1e6), "area of boundaries");
+
+		let hull = MultiPolygon(
+			boundaries
+				.0
+				.iter()
+				.cloned()
+				.fold1(|a, b| a.union(&b).concave_hull(2.0))
+				.map(|p| vec![p])
+				.unwrap_or_default(),
+		);
+
+		let outlines = if route.format == OutputFormat::Map {
+			info!(route=%route.name, "loading geojson outlines");
+			load_polygons(&route.outlines).await?
+		} else {
+			MultiPolygon(Vec::new())
+		};
+
+		let geocodes = if route.geocode_keys.is_empty() {
+			debug!(route=%route.name, "no geocode keys, not loading geocodes");
+			HashMap::new()
+		} else {
+			info!(route=%route.name, keys=?route.geocode_keys, "loading geojson geocodes");
+			load_geocodes(&route.geocodes, &route.geocode_keys).await?
+		};
+
+		Ok(Self {
+			boundaries,
+			named,
+			boundaries_area,
+			hull,
+			outlines,
+			geocodes,
+		})
+	}
+
+	/// Names of the boundaries that an alert's areas touch.
+	pub fn affected(&self, cap: &Cap) -> Vec<String> {
+		self.named
+			.iter()
+			.filter(|b| {
+				cap.info()
+					.areas
+					.iter()
+					.flat_map(|a| a.polygons.iter())
+					.any(|p| b.polygons.intersects(p) || b.polygons.contains(p))
+			})
+			.map(|b| b.name.clone())
+			.collect()
+	}
+}
+
+/// One of the areas we care about, like a site or a region.
+#[derive(Clone, Debug)]
+pub struct Boundary {
+	/// The `name` property of its features, or the name of its file.
+	pub name: String,
+	pub polygons: MultiPolygon<f64>,
+}
+
+/// Load boundaries, grouping features with the same name together.
+pub async fn load_boundaries(path: impl AsRef<Path>) -> Result<Vec<Boundary>> {
+	let path = path.as_ref();
+	let mut files = Vec::new();
+	for entry in glob::glob(path.join("*.geojson").display().to_string().as_str())? {
+		files.push(tokio::spawn(async {
+			let file = entry?;
+			let stem = file
+				.file_stem()
+				.map_or_else(String::new, |s| s.to_string_lossy().into());
+			Ok::<_, color_eyre::eyre::Report>((stem, read_geojson(file).await?))
+		}));
+	}
+
+	let mut boundaries: Vec<Boundary> = Vec::new();
+	for loaded in try_join_all(files).await? {
+		let (stem, geojson) = loaded?;
+		let features = match geojson {
+			GeoJson::FeatureCollection(fc) => fc.features,
+			GeoJson::Feature(f) => vec![f],
+			geometry @ GeoJson::Geometry(_) => {
+				let polys = only_polys(quick_collection(&geometry)?);
+				add_boundary(&mut boundaries, stem, polys);
+				continue;
+			}
+		};
+
+		for feature in features {
+			let name = feature
+				.properties
+				.as_ref()
+				.and_then(|p| p.get("name"))
+				.and_then(Value::as_str)
+				.map_or_else(|| stem.clone(), String::from);
+			let polys = only_polys(quick_collection(&GeoJson::Feature(feature))?);
+			add_boundary(&mut boundaries, name, polys);
+		}
+	}
+
+	debug!(
+		?path,
+		names=?boundaries.iter().map(|b| &b.name).collect::<Vec<_>>(),
+		"loaded {} boundaries",
+		boundaries.len()
+	);
+	Ok(boundaries)
+}
+
+fn add_boundary(boundaries: &mut Vec<Boundary>, name: String, polys: Vec<Polygon<f64>>) {
+	if polys.is_empty() {
+		return;
+	}
+
+	match boundaries.iter_mut().find(|b| b.name == name) {
+		Some(boundary) => boundary.polygons.0.extend(polys),
+		None => boundaries.push(Boundary {
+			name,
+			polygons: MultiPolygon(polys),
+		}),
+	}
+}
+
+/// Merge the polygons of all boundaries into one shape, without overlaps.
+fn merge(named: &[Boundary]) -> MultiPolygon<f64> {
+	named
+		.iter()
+		.map(|b| b.polygons.clone())
+		.tree_fold1(|a, b| a.union(&b))
+		.unwrap_or_else(|| MultiPolygon(Vec::new()))
+}
+
+/// How much of the boundaries an alert must cover to be kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overlap {
+	/// Fraction of the total area of the boundaries, from 0 to 1.
+	Fraction(f64),
+
+	/// Area in square kilometres.
+	Area(f64),
+}
+
+impl FromStr for Overlap {
+	type Err = String;
+
+	/// Either a fraction (`0.1`) or percentage (`10%`), or an area in km² (`5km2`).
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim();
+		let number = |n: &str| {
+			n.trim()
+				.parse::<f64>()
+				.ok()
+				.filter(|n| n.is_finite() && *n >= 0.0)
+				.ok_or_else(|| format!("invalid overlap: {}", s))
+		};
+
+		if let Some(km2) = s.strip_suffix("km2").or_else(|| s.strip_suffix("km²")) {
+			Ok(Self::Area(number(km2)?))
+		} else if let Some(percent) = s.strip_suffix('%') {
+			Ok(Self::Fraction(number(percent)? / 100.0))
+		} else {
+			match number(s)? {
+				n if n <= 1.0 => Ok(Self::Fraction(n)),
+				_ => Err(format!(
+					"overlap fraction must be between 0 and 1 (or use `%` or `km2`): {}",
+					s
+				)),
+			}
+		}
+	}
+}
+
+impl Overlap {
+	/// Whether an area of overlap, in square metres, is enough.
+	pub fn is_met(self, overlap: f64, boundaries_area: f64) -> bool {
+		match self {
+			Self::Fraction(fraction) => overlap >= fraction * boundaries_area,
+			Self::Area(km2) => overlap >= km2 * 1e6,
+		}
+	}
+}
+
+/// Area of polygons on the Earth, in square metres.
+pub fn area(polys: &MultiPolygon<f64>) -> f64 {
+	polys
+		.0
+		.iter()
+		.map(|p| p.chamberlain_duquette_unsigned_area())
+		.sum()
+}
+
+/// Grow polygons by a distance in kilometres.
+///
+/// That's the union of the polygons with a band along each of their edges and a disc at each of
+/// their vertices, which is close enough at the scale of alert areas.
+///
+/// That takes a couple of unions per vertex, so the polygons are first simplified to within a tenth
+/// of the distance, and grown by that much more to make up for it. Detailed boundaries have far
+/// more vertices than matter at the scale of a buffer, so that's much quicker.
+pub fn buffer(polys: &MultiPolygon<f64>, km: f64) -> MultiPolygon<f64> {
+	let tolerance = km / 10.0;
+	let polys = polys.simplify(&(tolerance / KM_PER_DEGREE));
+	let km = km + tolerance;
+
+	// rings simplified down to a line are still covered by the bands and discs along them
+	let valid = |r: &&LineString<f64>| r.0.len() >= 4;
+	let mut parts = polys
+		.0
+		.iter()
+		.filter(|p| valid(&p.exterior()))
+		.map(|p| {
+			Polygon::new(
+				p.exterior().clone(),
+				p.interiors().iter().filter(valid).cloned().collect(),
+			)
+		})
+		.collect::<Vec<_>>();
+	for poly in &polys.0 {
+		for ring in once(poly.exterior()).chain(poly.interiors()) {
+			for line in ring.lines() {
+				let ruler = CheapRuler::new(line.start.y, DistanceUnit::Kilometers);
+				let (start, end) = (line.start_point(), line.end_point());
+				let bearing = ruler.bearing(&start, &end);
+				let band = [
+					ruler.destination(&start, km, bearing - 90.0),
+					ruler.destination(&end, km, bearing - 90.0),
+					ruler.destination(&end, km, bearing + 90.0),
+					ruler.destination(&start, km, bearing + 90.0),
+				];
+				parts.push(Polygon::new(
+					band.iter().map(|p| Coordinate::from(*p)).collect(),
+					Vec::new(),
+				));
+			}
+
+			parts.extend(ring.points_iter().map(|p| disc(p, km)));
+		}
+	}
+
+	parts
+		.into_iter()
+		.map(|p| MultiPolygon(vec![p]))
+		.tree_fold1(|a, b| a.union(&b))
+		.unwrap_or_else(|| MultiPolygon(Vec::new()))
+}
+
+/// Approximate a circle around a point, with a radius in kilometres.
+fn disc(centre: Point<f64>, km: f64) -> Polygon<f64> {
+	const EDGES: u8 = 16;
+
+	let ruler = CheapRuler::new(centre.y(), DistanceUnit::Kilometers);
+	Polygon::new(
+		(0..EDGES)
+			.map(|i| {
+				let bearing = 360.0 * (f64::from(i) / f64::from(EDGES));
+				Coordinate::from(ruler.destination(&centre, km, bearing))
+			})
+			.collect::<LineString<f64>>(),
+		Vec::new(),
+	)
+}
+
+/// Index the polygons of features by their key properties, as geocodes.
+///
+/// Each key is a geocode name, which is also the property holding its values, or `NAME=PROPERTY`.
+/// With the key `UGC`, a feature with `{ "UGC": "AKZ017" }` in its properties is the area of a CAP
+/// with that geocode. Other properties are ignored.
+pub async fn load_geocodes(
+	path: impl AsRef<Path>,
+	keys: &[String],
+) -> Result<HashMap<Geocode, Vec<Polygon<f64>>>> {
+	let path = path.as_ref();
+	let keys = keys
+		.iter()
+		.map(|key| match key.split_once('=') {
+			Some((name, property)) => (name.trim(), property.trim()),
+			None => (key.trim(), key.trim()),
+		})
+		.collect::<Vec<_>>();
+	let mut files = Vec::new();
+	for entry in glob::glob(path.join("*.geojson").display().to_string().as_str())? {
+		files.push(tokio::spawn(async { read_geojson(entry?).await }));
+	}
+
+	let mut geocodes = HashMap::<Geocode, Vec<Polygon<f64>>>::new();
+	for geojson in try_join_all(files).await? {
+		let features = match geojson? {
+			GeoJson::FeatureCollection(fc) => fc.features,
+			GeoJson::Feature(f) => vec![f],
+			GeoJson::Geometry(_) => continue,
+		};
+
+		for feature in features {
+			let codes = match &feature.properties {
+				Some(properties) => keys
+					.iter()
+					.filter_map(|(name, property)| {
+						let value = match properties.get(*property)? {
+							Value::String(s) => s.clone(),
+							Value::Number(n) => n.to_string(),
+							_ => return None,
+						};
+
+						Some(Geocode {
+							name: (*name).into(),
+							value,
+						})
+					})
+					.collect::<Vec<_>>(),
+				None => continue,
+			};
+
+			if codes.is_empty() {
+				continue;
+			}
+
+			let polys = only_polys(quick_collection(&GeoJson::Feature(feature))?);
+			if polys.is_empty() {
+				continue;
+			}
+
+			for code in codes {
+				geocodes
+					.entry(code)
+					.or_default()
+					.extend(polys.iter().cloned());
+			}
+		}
+	}
+
+	debug!(?path, "indexed {} geocodes", geocodes.len());
+	Ok(geocodes)
+}
 
 pub async fn load_polygons(path: impl AsRef<Path>) -> Result<MultiPolygon<f64>> {
 	let path = path.as_ref();
@@ -39,8 +433,7 @@ pub async fn load_geo_dir(path: impl AsRef<Path>) -> Result<GeometryCollection<f
 		.into_iter()
 		.collect::<Result<Vec<_>, _>>()?
 		.into_iter()
-		.map(|gc| gc.0)
-		.flatten()
+		.flat_map(|gc| gc.0)
 		.collect::<Vec<_>>();
 
 	Ok(GeometryCollection(gs))
@@ -62,6 +455,11 @@ pub async fn debug_write_geojson(name: &str, polys: &MultiPolygon<f64>) -> Resul
 }
 
 async fn load_geojson(path: PathBuf) -> Result<GeometryCollection<f64>> {
+	let geo = read_geojson(path).await?;
+	Ok(quick_collection(&geo)?)
+}
+
+async fn read_geojson(path: PathBuf) -> Result<GeoJson> {
 	debug!(?path, "reading geojson");
 
 	let mut file = File::open(&path).await?;
@@ -78,7 +476,7 @@ async fn load_geojson(path: PathBuf) -> Result<GeometryCollection<f64>> {
 	let geo = GeoJson::from_reader(&contents[..])?;
 	trace!(?path, ?geo, "parsed geojson");
 
-	Ok(quick_collection(&geo)?)
+	Ok(geo)
 }
 
 pub fn only_polys(geometries: impl IntoIterator<Item = Geometry<f64>>) -> Vec<Polygon<f64>> {
@@ -93,3 +491,109 @@ pub fn only_polys(geometries: impl IntoIterator<Item = Geometry<f64>>) -> Vec<Po
 		.flatten()
 		.collect()
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A square about 8 by 11 km in the Tararua Range.
+	fn square(west: f64) -> MultiPolygon<f64> {
+		let (north, east, south) = (-40.6, west + 0.1, -40.7);
+		MultiPolygon(vec![Polygon::new(
+			vec![
+				(west, north),
+				(east, north),
+				(east, south),
+				(west, south),
+				(west, north),
+			]
+			.into(),
+			Vec::new(),
+		)])
+	}
+
+	fn perimeter(polys: &MultiPolygon<f64>) -> f64 {
+		polys.0[0]
+			.exterior()
+			.lines()
+			.map(|line| {
+				CheapRuler::new(line.start.y, DistanceUnit::Kilometers)
+					.distance(&line.start_point(), &line.end_point())
+			})
+			.sum()
+	}
+
+	#[test]
+	fn buffer_grows_by_at_least_the_distance() {
+		let square = square(175.5);
+		let grown = buffer(&square, 1.0);
+		assert!(grown.contains(&square.0[0]));
+
+		let ruler = CheapRuler::new(-40.65, DistanceUnit::Kilometers);
+		for &(point, bearing) in &[
+			(Point::new(175.55, -40.6), 0.0),
+			(Point::new(175.6, -40.65), 90.0),
+			(Point::new(175.55, -40.7), 180.0),
+			(Point::new(175.5, -40.65), 270.0),
+		] {
+			assert!(grown.contains(&ruler.destination(&point, 0.99, bearing)));
+			assert!(!grown.contains(&ruler.destination(&point, 1.2, bearing)));
+		}
+
+		let (before, after) = (area(&square) / 1e6, area(&grown) / 1e6);
+		let edge = perimeter(&square);
+		assert!(
+			after > before + edge,
+			"{} km² grown to {} km²",
+			before,
+			after
+		);
+		assert!(
+			after < before + edge * 1.15 + 4.0,
+			"{} km² grown to {} km²",
+			before,
+			after
+		);
+	}
+
+	#[test]
+	fn buffer_keeps_detail_that_matters() {
+		let centre = Point::new(175.5, -40.65);
+		let ruler = CheapRuler::new(centre.y(), DistanceUnit::Kilometers);
+		let circle = (0..720)
+			.map(|i| ruler.destination(&centre, 5.0, f64::from(i) / 2.0))
+			.map(Coordinate::from)
+			.collect::<LineString<f64>>();
+		let grown = buffer(
+			&MultiPolygon(vec![Polygon::new(circle.clone(), Vec::new())]),
+			1.0,
+		);
+
+		assert!(circle.points_iter().all(|p| grown.contains(&p)));
+		for bearing in (0..360).step_by(15) {
+			let bearing = f64::from(bearing);
+			assert!(grown.contains(&ruler.destination(&centre, 5.95, bearing)));
+			assert!(!grown.contains(&ruler.destination(&centre, 6.3, bearing)));
+		}
+	}
+
+	#[test]
+	fn merge_counts_overlaps_once() {
+		let named = vec![
+			Boundary {
+				name: "west".into(),
+				polygons: square(175.5),
+			},
+			Boundary {
+				name: "east".into(),
+				polygons: square(175.55),
+			},
+		];
+
+		let merged = merge(&named);
+		assert_eq!(merged.0.len(), 1);
+
+		let one = area(&named[0].polygons);
+		assert!((area(&merged) - one * 1.5).abs() < one * 0.01);
+	}
+}
@@ -1,22 +1,55 @@
-use std::{collections::HashSet, env::var, path::PathBuf};
-
-use color_eyre::eyre::Result;
+use std::{
+	collections::{HashMap, HashSet},
+	env::var,
+	path::PathBuf,
+	sync::Arc,
+};
+
+use chrono::{Duration, Utc};
+use color_eyre::eyre::{eyre, Result};
 use futures::future::try_join_all;
-use geo::prelude::{Contains, Intersects};
+use geo::{
+	prelude::{Contains, Intersects},
+	MultiPolygon,
+};
+use geo_booleanop::boolean::BooleanOp;
+use itertools::Itertools;
 use structopt::StructOpt;
 use tokio::{fs::File, io::AsyncWriteExt};
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 
-use output::OutputFormat;
+use output::{Out, OutputFormat};
 
-use crate::output::Out;
+use crate::{
+	cache::{Cache, FailedItem, ItemState},
+	cap::{Cap, MsgType},
+	config::{FacebookSink, Route, WebhookSink},
+	geodirs::Geodata,
+	signature::Verifier,
+};
 
+mod cache;
 mod cap;
+mod config;
 mod discord;
+mod email;
 mod facebook;
 mod feed;
+mod filter;
 mod geodirs;
+mod mastodon;
+mod matrix;
 mod output;
+mod push;
+mod retry;
+mod signature;
+mod slack;
+mod teams;
+mod telegram;
+#[cfg(test)]
+mod testing;
+mod watch;
+mod webhook;
 
 #[derive(Clone, Debug, StructOpt)]
 pub struct Args {
@@ -32,6 +65,13 @@ pub struct Args {
 	#[structopt(short, long)]
 	quiet: bool,
 
+	/// Path to a TOML config file defining routes.
+	///
+	/// Each route has its own feeds, filters, format, and sinks. If `--cap` is also given, the
+	/// command-line options make up an additional route named `default`.
+	#[structopt(long)]
+	config: Option<PathBuf>,
+
 	/// URL(s) for the Atom/RSS feed to CAP alerts.
 	#[structopt(long)]
 	cap: Vec<String>,
@@ -40,18 +80,131 @@ pub struct Args {
 	#[structopt(long, default_value = "Minor")]
 	severity: cap::Severity,
 
+	/// Preferred languages for alerts that come in several, in order (e.g. `en-NZ,mi`).
+	///
+	/// When none of these are available, the first language the sender gave is used.
+	#[structopt(long, use_delimiter = true)]
+	language: Vec<String>,
+
+	/// Statuses of alerts to post (`Actual`, `Exercise`, `System`, `Test`, `Draft`).
+	///
+	/// Alerts with a status other than `Actual` have it in front of their headline, e.g. `EXERCISE:`.
+	#[structopt(long, use_delimiter = true, default_value = "Actual")]
+	status: Vec<String>,
+
+	/// Scopes of alerts to post (`Public`, `Restricted`, `Private`).
+	#[structopt(long, use_delimiter = true, default_value = "Public")]
+	scope: Vec<String>,
+
+	/// Maximum age of alerts to post, in hours since they were sent.
+	///
+	/// Useful on a first run with an empty cache, so the whole history of the feed isn't posted.
+	/// Alerts that have already expired are never posted.
+	#[structopt(long)]
+	max_age: Option<u32>,
+
+	/// Expression to select alerts by their fields (e.g. `urgency in [Immediate, Expected] and event ~ "Thunderstorm"`).
+	///
+	/// Compare fields with `=`, `!=`, `~` (contains), `!~`, `in [...]`, `not in [...]`, or `<`, `<=`, `>`, `>=`
+	/// for severity, urgency, certainty, and numbers; combine with `and`, `or`, `not`, and parentheses.
+	/// Fields are `status`, `scope`, `msg_type`, `sender`, `source`, `category`, `event`, `urgency`,
+	/// `severity`, `certainty`, `sender_name`, `language`, `headline`, `audience`, `response_type`,
+	/// `area`, `event_code.NAME`, and `parameter.NAME` (e.g. `parameter.ColourCode`).
+	#[structopt(long)]
+	filter: Option<filter::Filter>,
+
 	/// Path to a folder container GeoJSON files with polygons that demarcate areas you care about.
 	#[structopt(long, default_value = "_boundaries")]
 	boundaries: PathBuf,
 
+	/// Distance to grow boundaries by, in kilometres, so alerts just outside them come through too.
+	#[structopt(long, default_value = "0")]
+	buffer: f64,
+
+	/// How much of the boundaries an alert must cover to be kept, instead of merely touching them.
+	///
+	/// Either a fraction of the area of the boundaries (`0.1` or `10%`), or an area in square
+	/// kilometres (`5km2`). Areas are measured on the Earth's surface, not in degrees.
+	#[structopt(long)]
+	min_overlap: Option<geodirs::Overlap>,
+
 	/// Path to a folder container GeoJSON files with polygons for outlines of countries or areas, to render basemaps.
 	#[structopt(long, default_value = "_outlines")]
 	outlines: PathBuf,
 
+	/// Path to a folder containing GeoJSON files with polygons for CAP geocodes.
+	///
+	/// A feature is used for a geocode when it has a property named after the geocode's `valueName`
+	/// with the geocode's value, e.g. `"UGC": "AKZ017"`. It's only used for areas without polygons.
+	/// Only the properties given with `--geocode-key` are looked at.
+	#[structopt(long, default_value = "_geocodes")]
+	geocodes: PathBuf,
+
+	/// Geocode `valueName`s to resolve with the geocodes folder (e.g. `UGC,SAME`).
+	///
+	/// Each is the name of the feature property holding that kind of code, or `VALUENAME=PROPERTY`
+	/// when the property is named differently, e.g. `NZ-CDEM=code`.
+	#[structopt(long, use_delimiter = true)]
+	geocode_key: Vec<String>,
+
 	/// Path to the cache database (used to avoid double-posting).
 	#[structopt(long, default_value = "_cache")]
 	cache_db: PathBuf,
 
+	/// Keep running and poll feeds periodically instead of exiting after one pass.
+	///
+	/// Boundaries and outlines are loaded once at startup. Stops cleanly on SIGTERM or Ctrl-C.
+	#[structopt(long, alias = "daemon")]
+	watch: bool,
+
+	/// Default interval between polls of each feed in watch mode, in seconds.
+	///
+	/// When the feed response has `Cache-Control: max-age` or `Expires` headers, those are used instead.
+	#[structopt(long, default_value = "300")]
+	interval: u64,
+
+	/// Minimum interval between polls of each feed in watch mode, in seconds.
+	#[structopt(long, default_value = "60")]
+	min_interval: u64,
+
+	/// In watch mode, post a short reminder when an alert comes into effect, and a notice when it expires.
+	///
+	/// The onset reminder is only sent for alerts that were posted before their onset.
+	#[structopt(long)]
+	reminders: bool,
+
+	/// Generic webhook to alert about CAPs that fail to fetch or parse.
+	///
+	/// The failures are given to the template as `message`.
+	#[structopt(long)]
+	admin_webhook_url: Option<String>,
+
+	/// Path to a Handlebars template for the admin webhook request body.
+	#[structopt(long)]
+	admin_webhook_template: Option<PathBuf>,
+
+	/// Retry feed items that were seen before but never delivered (failed to fetch or to send).
+	///
+	/// This includes items that have since dropped off the feed. In watch mode, they're retried on every poll,
+	/// up to 10 times or for 48 hours after they were first seen.
+	#[structopt(long)]
+	retry_failed: bool,
+
+	/// What to do with CAPs that aren't signed by a trusted key (`ignore`, `accept`, `flag`, `drop`).
+	///
+	/// With `ignore`, signatures aren't checked at all. With `accept`, unsigned or invalid alerts are
+	/// logged but posted as usual; with `flag` they're marked as unverified in the output; and with
+	/// `drop` they're not posted, and count as failures. Verified alerts are always marked as such.
+	#[structopt(long, default_value = "ignore")]
+	signature_policy: signature::SignaturePolicy,
+
+	/// Path to a folder containing PEM files with the certificates or public keys of trusted senders.
+	///
+	/// Each file is named after the `<sender>` of the alerts its keys sign (e.g. `alerts@metservice.com.pem`),
+	/// and alerts only verify against the keys of their own sender.
+	#[structopt(long, default_value = "_trusted")]
+	trusted_keys: PathBuf,
+
 	/// Type of output to send to chatrooms (`json`, `text`, `text+map`).
 	#[structopt(long, default_value = "text+map")]
 	format: OutputFormat,
@@ -89,6 +242,137 @@ pub struct Args {
 	/// Discord webhook URL to use to post messages.
 	#[structopt(long)]
 	discord_webhook_url: Option<String>,
+
+	/// What to do with Discord messages for alerts that were cancelled or have expired (`strike`, `delete`).
+	///
+	/// Updates to an alert edit its original message instead.
+	#[structopt(long, default_value = "strike")]
+	discord_stale: discord::StaleAction,
+
+	/// Slack incoming webhook URL to use to post messages.
+	///
+	/// Webhooks can't upload files, so maps are not sent this way: use a token and channel for that.
+	#[structopt(long)]
+	slack_webhook_url: Option<String>,
+
+	/// Slack bot token, with the `chat:write` and `files:write` scopes.
+	#[structopt(long)]
+	slack_token: Option<String>,
+
+	/// Slack channel ID to post in, when using a token.
+	#[structopt(long)]
+	slack_channel: Option<String>,
+
+	/// Base URL of the Slack Web API (for testing against a mock server).
+	#[structopt(long, default_value = "https://slack.com/api")]
+	slack_api_url: String,
+
+	/// Microsoft Teams incoming webhook or Workflows URL to use to post messages.
+	#[structopt(long)]
+	teams_webhook_url: Option<String>,
+
+	/// Matrix homeserver base URL (e.g. `https://matrix.org`).
+	#[structopt(long)]
+	matrix_homeserver: Option<String>,
+
+	/// Matrix access token of the user to post as.
+	#[structopt(long)]
+	matrix_token: Option<String>,
+
+	/// Matrix room ID to post in (e.g. `!abcdef:matrix.org`). The user must already be in the room.
+	#[structopt(long)]
+	matrix_room: Option<String>,
+
+	/// Telegram bot token.
+	#[structopt(long)]
+	telegram_token: Option<String>,
+
+	/// Telegram chat ID to post in. The bot must already be in the chat.
+	#[structopt(long)]
+	telegram_chat: Option<String>,
+
+	/// Base URL of the Telegram Bot API (for testing against a mock server).
+	#[structopt(long, default_value = "https://api.telegram.org")]
+	telegram_api_url: String,
+
+	/// URL of a generic webhook to post alerts to.
+	#[structopt(long)]
+	webhook_url: Option<String>,
+
+	/// Path to a Handlebars template for the webhook request body.
+	///
+	/// The template has access to `route`, `caps` (the full CAP data), `message` (the formatted text),
+	/// and `image` (the map as base64, with `--webhook-image base64`). Values are escaped for use in
+	/// JSON strings; use `{{{json value}}}` to insert a value as JSON. Without a template, the body
+	/// is all of that as JSON.
+	#[structopt(long)]
+	webhook_template: Option<PathBuf>,
+
+	/// Extra header to send to the webhook, as `Name: value` (can have multiple).
+	#[structopt(long, parse(try_from_str = config::parse_header))]
+	webhook_header: Vec<(String, String)>,
+
+	/// Secret to sign webhook bodies with (HMAC-SHA256).
+	#[structopt(long)]
+	webhook_secret: Option<String>,
+
+	/// Header to put the webhook signature in, as `sha256=HEX`.
+	#[structopt(long, default_value = "X-Capchat-Signature")]
+	webhook_signature_header: String,
+
+	/// How to send the map image to the webhook (`none`, `base64`, `multipart`).
+	///
+	/// With `multipart`, the body goes in a `payload` part and the image in an `image` part.
+	#[structopt(long, default_value = "none")]
+	webhook_image: webhook::ImageMode,
+
+	/// SMTP server to send emails through.
+	#[structopt(long)]
+	smtp_host: Option<String>,
+
+	/// SMTP server port.
+	#[structopt(long, default_value = "587")]
+	smtp_port: u16,
+
+	/// How to secure the SMTP connection (`none`, `starttls`, `tls`).
+	#[structopt(long, default_value = "starttls")]
+	smtp_security: email::SmtpSecurity,
+
+	/// SMTP username, if the server needs authentication.
+	#[structopt(long)]
+	smtp_username: Option<String>,
+
+	/// SMTP password, if the server needs authentication.
+	#[structopt(long)]
+	smtp_password: Option<String>,
+
+	/// Address to send emails from (e.g. `CAP Alerts <alerts@example.com>`).
+	#[structopt(long)]
+	email_from: Option<String>,
+
+	/// Address to send emails to (can have multiple).
+	#[structopt(long)]
+	email_to: Vec<String>,
+
+	/// ntfy-compatible topic URL to send push notifications to (e.g. `https://ntfy.sh/mytopic`).
+	#[structopt(long)]
+	push_url: Option<String>,
+
+	/// Access token for the push server, if the topic is protected.
+	#[structopt(long)]
+	push_token: Option<String>,
+
+	/// Mastodon instance to post statuses to (e.g. `https://mastodon.social`).
+	#[structopt(long)]
+	mastodon_instance: Option<String>,
+
+	/// Mastodon access token, with the `write:statuses` and `write:media` scopes.
+	#[structopt(long)]
+	mastodon_token: Option<String>,
+
+	/// Visibility of Mastodon statuses (`public`, `unlisted`, `private`), instead of the account default.
+	#[structopt(long)]
+	mastodon_visibility: Option<String>,
 }
 
 #[tokio::main]
@@ -118,97 +402,427 @@ async fn main() -> Result<()> {
 
 	debug!(?args, "parsed arguments");
 
-	debug!(path=?args.cache_db, "opening sled database");
-	let db = sled::open(&args.cache_db)?;
-	// db.drop_tree("cache")?; // DEV
-	let cache = db.open_tree("cache")?;
+	let config::Config { routes, admin } = config::load(&args).await?;
+	let cache = Cache::open(&args.cache_db)?;
+	let verifier = Verifier::load(args.signature_policy, &args.trusted_keys)
+		.await?
+		.map(Arc::new);
+
+	let res = if args.watch {
+		let mut loaded = Vec::with_capacity(routes.len());
+		for route in routes {
+			let geo = Geodata::load(&route).await?;
+			loaded.push((route, geo));
+		}
+
+		watch::run(&args, &cache, &loaded, admin.as_ref(), verifier).await
+	} else {
+		once(&args, &routes, admin.as_ref(), verifier, &cache).await
+	};
+
+	// flush even on failure, so what did get sent isn't sent again
+	cache.flush().await?;
+	res?;
 
-	let mut caps = try_join_all(args.cap.iter().cloned().map(move |url| {
-		let tree = cache.clone();
-		tokio::spawn(async move { feed::fetch_feed(tree, url).await })
+	info!("all done");
+	Ok(())
+}
+
+async fn once(
+	args: &Args,
+	routes: &[Route],
+	admin: Option<&WebhookSink>,
+	verifier: Option<Arc<Verifier>>,
+	cache: &Cache,
+) -> Result<()> {
+	for route in routes {
+		// a failure here shouldn't stop new alerts from going out
+		if let Err(err) = tidy(route, cache).await {
+			error!(route=%route.name, "failed to tidy up posted messages: {}", err);
+		}
+	}
+
+	let urls = routes
+		.iter()
+		.flat_map(|r| r.feeds.iter().cloned())
+		.unique()
+		.collect::<Vec<_>>();
+
+	let feeds = try_join_all(urls.iter().cloned().map(|url| {
+		let cache = cache.clone();
+		let retry = args.retry_failed;
+		let verifier = verifier.clone();
+		tokio::spawn(async move { feed::fetch_feed(cache, url, retry, verifier).await })
 	}))
 	.await?
 	.into_iter()
-	.collect::<Result<Vec<_>, _>>()?
-	.into_iter()
-	.flatten()
-	.collect::<HashSet<_>>();
+	.collect::<Result<Vec<_>, _>>()?;
+
+	let failed = feeds
+		.iter()
+		.flat_map(|feed| feed.failed.iter().cloned())
+		.collect::<Vec<_>>();
+	report_failures(admin, &failed).await;
+
+	let fetched = urls
+		.into_iter()
+		.zip(feeds.into_iter().map(|feed| feed.caps))
+		.collect::<HashMap<_, _>>();
+
+	debug!(
+		"fetched {} new caps",
+		fetched.values().map(Vec::len).sum::<usize>()
+	);
+
+	let mut failed = 0;
+	let mut undelivered = HashSet::new();
+	for route in routes {
+		let caps = route
+			.feeds
+			.iter()
+			.filter_map(|url| fetched.get(url))
+			.flatten()
+			.cloned()
+			.collect::<HashSet<_>>();
+
+		if caps.is_empty() {
+			info!(route=%route.name, "nothing to do");
+			continue;
+		}
 
-	debug!("fetched {} new caps", caps.len());
+		let items = caps.iter().map(|cap| cap.item.clone()).collect::<Vec<_>>();
+		let geo = Geodata::load(route).await?;
+		if let Err(err) = process(route, cache, &geo, caps).await {
+			error!(route=%route.name, "failed to process caps: {}", err);
+			failed += 1;
+			undelivered.extend(items);
+		}
+	}
 
-	if caps.is_empty() {
-		info!("nothing to do");
-		return Ok(());
+	for cap in fetched.values().flatten() {
+		if !undelivered.contains(&cap.item) {
+			cache.set_item_state(&cap.item, ItemState::Delivered)?;
+		}
 	}
 
-	info!("loading geojson boundaries");
-	let bounds = geodirs::load_polygons(&args.boundaries).await?;
+	if failed > 0 {
+		Err(eyre!("{} of {} routes failed", failed, routes.len()))
+	} else {
+		Ok(())
+	}
+}
+
+pub async fn process(
+	route: &Route,
+	cache: &Cache,
+	geo: &Geodata,
+	caps: HashSet<Cap>,
+) -> Result<Vec<Cap>> {
+	let mut caps = caps
+		.into_iter()
+		.map(|mut cap| {
+			cap.select_language(&route.language);
+			cap.resolve_geocodes(&geo.geocodes);
+			cap.supersedes = cache.originals(route, &cap)?;
+			Ok(cap)
+		})
+		.collect::<Result<HashSet<_>>>()?;
+
+	let now = Utc::now();
+	caps.retain(|cap| {
+		if cap.info().expires.is_some_and(|expires| expires <= now) {
+			debug!(route=%route.name, guid=%cap.guid, "dropping alert that has already expired");
+			false
+		} else if !cap.is_follow_up()
+			&& route.max_age.is_some_and(|hours| {
+				now.signed_duration_since(cap.date_sent) > Duration::hours(hours.into())
+			}) {
+			debug!(route=%route.name, guid=%cap.guid, sent=%cap.date_sent, "dropping alert older than the maximum age");
+			false
+		} else if !route.statuses.iter().any(|s| s.eq_ignore_ascii_case(&cap.status)) {
+			debug!(route=%route.name, guid=%cap.guid, status=%cap.status, "dropping alert with unwanted status");
+			false
+		} else if !route.scopes.iter().any(|s| s.eq_ignore_ascii_case(&cap.scope)) {
+			debug!(route=%route.name, guid=%cap.guid, scope=%cap.scope, "dropping alert with unwanted scope");
+			false
+		} else if cap.msg_type == MsgType::Cancel && !cap.is_follow_up() {
+			debug!(route=%route.name, guid=%cap.guid, "dropping cancellation of an alert we never posted");
+			false
+		} else {
+			true
+		}
+	});
+
+	let bounds = &geo.boundaries;
 	if !bounds.0.is_empty() {
-		info!(boundaries=%bounds.0.len(), "checking intersections");
+		info!(route=%route.name, boundaries=%bounds.0.len(), "checking intersections");
 		caps.retain(|cap| {
-			cap.info.areas.is_empty()
-				|| cap
-					.info
-					.areas
-					.iter()
-					.map(|a| &a.polygons)
-					.flatten()
-					.any(|p| bounds.intersects(p) || bounds.contains(p))
+			cap.is_follow_up() || cap.info().areas.is_empty() || overlaps(route, geo, cap)
 		});
-		info!(caps=%caps.len(), "filtered caps against boundaries");
+		info!(route=%route.name, caps=%caps.len(), "filtered caps against boundaries");
 	}
 
-	caps.retain(|cap| cap.info.severity >= args.severity);
-	info!(caps=%caps.len(), severity=?args.severity, "filtered caps against severity");
+	caps.retain(|cap| cap.is_follow_up() || cap.info().severity >= route.severity);
+	info!(route=%route.name, caps=%caps.len(), severity=?route.severity, "filtered caps against severity");
+
+	if let Some(filter) = &route.filter {
+		caps.retain(|cap| cap.is_follow_up() || filter.matches(cap));
+		info!(route=%route.name, caps=%caps.len(), %filter, "filtered caps against expression");
+	}
 
 	if caps.is_empty() {
-		info!("nothing to do");
-		return Ok(());
-	}
-
-	info!("formatting for output");
-	let out = match args.format {
-		OutputFormat::Json => Out {
-			message: serde_json::to_string(&caps)?,
-			..Out::default()
-		},
-		OutputFormat::Text => output::text(caps)?,
-		OutputFormat::Map => output::text_with_map(caps, &args).await?,
+		info!(route=%route.name, "nothing to do");
+		return Ok(Vec::new());
+	}
+
+	let caps = caps
+		.into_iter()
+		.map(|mut cap| {
+			cap.affects = geo.affected(&cap);
+			cap
+		})
+		.collect::<Vec<_>>();
+	let mut outs: HashMap<Vec<String>, Out> = HashMap::new();
+	let mut failed = Vec::new();
+
+	for sink in route.sinks() {
+		let key = format!("{}/{}", route.name, sink);
+		let mut pending = Vec::with_capacity(caps.len());
+		for cap in &caps {
+			if cache.is_delivered(&key, cap)? {
+				debug!(route=%route.name, %sink, guid=%cap.guid, "already sent, skipping");
+			} else {
+				pending.push(cap.clone());
+			}
+		}
+
+		if pending.is_empty() {
+			continue;
+		}
+
+		// sinks with the same pending caps (usually all of them) share the output
+		let guids = pending
+			.iter()
+			.map(|cap| cap.guid.clone())
+			.collect::<Vec<_>>();
+		if !outs.contains_key(&guids) {
+			info!(route=%route.name, caps=%pending.len(), "formatting for output");
+			let out = output::render(pending.iter().cloned().collect(), route, geo).await?;
+			outs.insert(guids.clone(), out);
+		}
+
+		info!(route=%route.name, %sink, "sending");
+		match deliver(route, sink, cache, geo, &pending, &outs[&guids]).await {
+			Ok(()) => {
+				debug!(route=%route.name, %sink, "sent");
+				for cap in &pending {
+					cache.mark_delivered(&key, cap)?;
+				}
+			}
+			Err(err) => {
+				error!(route=%route.name, %sink, "failed to send: {:?}", err);
+				failed.push(sink);
+			}
+		}
+	}
+
+	for cap in &caps {
+		cache.mark_posted(route, cap)?;
+	}
+
+	if failed.is_empty() {
+		Ok(caps)
+	} else {
+		Err(eyre!("failed to send to {}", failed.join(", ")))
+	}
+}
+
+/// Post a short reminder about an alert that was already posted to all of a route's sinks.
+pub async fn remind(route: &Route, cache: &Cache, geo: &Geodata, cap: &Cap) -> Result<()> {
+	let out = Out {
+		message: format!("{}\n\n{}", output::headline(cap), output::when_where(cap)),
+		image: None,
 	};
 
-	if args.print {
-		println!("{}", &out.message);
+	let mut failed = Vec::new();
+	for sink in route.sinks() {
+		info!(route=%route.name, %sink, guid=%cap.guid, "sending reminder");
+		if let Err(err) = deliver(route, sink, cache, geo, std::slice::from_ref(cap), &out).await {
+			error!(route=%route.name, %sink, "failed to send reminder: {:?}", err);
+			failed.push(sink);
+		}
 	}
 
-	if let Some(path) = args.file {
-		let mut txt = path.clone();
-		txt.set_extension("txt");
-		info!(path=?txt, "writing output message");
-		File::create(txt)
-			.await?
-			.write_all(out.message.as_bytes())
-			.await?;
+	if failed.is_empty() {
+		Ok(())
+	} else {
+		Err(eyre!("failed to send to {}", failed.join(", ")))
+	}
+}
+
+/// Whether an alert's areas touch the boundaries, or cover enough of them if the route asks for that.
+fn overlaps(route: &Route, geo: &Geodata, cap: &Cap) -> bool {
+	let bounds = &geo.boundaries;
+	let polygons = cap.info().areas.iter().flat_map(|a| a.polygons.iter());
+	if !polygons
+		.clone()
+		.any(|p| bounds.intersects(p) || bounds.contains(p))
+	{
+		return false;
+	}
+
+	let min = match route.min_overlap {
+		Some(min) => min,
+		None => return true,
+	};
+
+	// areas of an alert may overlap each other, so they're merged to not count twice
+	let overlap = polygons
+		.cloned()
+		.map(|p| MultiPolygon(vec![p]))
+		.tree_fold1(|a, b| a.union(&b))
+		.map_or(0.0, |alert| geodirs::area(&alert.intersection(bounds)));
+
+	let met = min.is_met(overlap, geo.boundaries_area);
+	debug!(
+		route=%route.name,
+		guid=%cap.guid,
+		km2=%(overlap / 1e6),
+		fraction=%(overlap / geo.boundaries_area),
+		?min,
+		%met,
+		"measured overlap with boundaries"
+	);
+	met
+}
 
-		if let Some(ref bytes) = out.image {
-			let mut img = path.clone();
-			img.set_extension("png");
-			info!(path=?img, "writing output image");
-			File::create(img).await?.write_all(bytes).await?;
+/// Send caps to one of a route's sinks.
+async fn deliver(
+	route: &Route,
+	sink: &str,
+	cache: &Cache,
+	geo: &Geodata,
+	caps: &[Cap],
+	out: &Out,
+) -> Result<()> {
+	let missing = || eyre!("no {} sink configured", sink);
+	let progress = cache.progress(&format!("{}/{}", route.name, sink), caps);
+
+	match sink {
+		"print" => println!("{}", &out.message),
+		"file" => {
+			let path = route.file.as_ref().ok_or_else(missing)?;
+			let mut txt = path.clone();
+			txt.set_extension("txt");
+			info!(path=?txt, "writing output message");
+			File::create(txt)
+				.await?
+				.write_all(out.message.as_bytes())
+				.await?;
+
+			if let Some(ref bytes) = out.image {
+				let mut img = path.clone();
+				img.set_extension("png");
+				info!(path=?img, "writing output image");
+				File::create(img).await?.write_all(bytes).await?;
+			}
+		}
+		"facebook" => {
+			let FacebookSink { token, thread } = route.facebook.as_ref().ok_or_else(missing)?;
+			facebook::send(token, thread, out, &progress).await?
+		}
+		"slack" => {
+			let sink = route.slack.as_ref().ok_or_else(missing)?;
+			slack::send(sink, caps, out, &progress).await?
 		}
+		"teams" => {
+			let sink = route.teams.as_ref().ok_or_else(missing)?;
+			teams::send(route, sink, geo, caps, out).await?
+		}
+		"matrix" => {
+			let sink = route.matrix.as_ref().ok_or_else(missing)?;
+			matrix::post(route, sink, cache, geo, caps, out, &progress).await?
+		}
+		"telegram" => {
+			let sink = route.telegram.as_ref().ok_or_else(missing)?;
+			telegram::send(sink, out, &progress).await?
+		}
+		"webhook" => {
+			let sink = route.webhook.as_ref().ok_or_else(missing)?;
+			webhook::send(route, sink, caps, out).await?
+		}
+		"email" => email::send(route.email.as_ref().ok_or_else(missing)?, caps, out).await?,
+		"push" => push::send(route.push.as_ref().ok_or_else(missing)?, caps, out).await?,
+		"mastodon" => {
+			let sink = route.mastodon.as_ref().ok_or_else(missing)?;
+			mastodon::send(sink, caps, out, &progress).await?
+		}
+		"discord" => {
+			let sink = route.discord.as_ref().ok_or_else(missing)?;
+			discord::post(route, sink, cache, geo, caps, out, &progress).await?
+		}
+		_ => return Err(eyre!("unknown sink: {}", sink)),
 	}
 
-	if let (Some(token), Some(thread)) = (&args.facebook_token, &args.facebook_thread) {
-		info!(%thread, "sending to workplace");
-		facebook::send(token, thread, &out).await?;
-		debug!(%thread, "sent to workplace");
+	progress.clear()
+}
+
+/// Log a summary of CAPs that failed to fetch or parse, and alert the admin webhook about new ones.
+pub async fn report_failures(admin: Option<&WebhookSink>, failed: &[FailedItem]) {
+	if failed.is_empty() {
+		return;
 	}
 
-	if let Some(webhook_url) = &args.discord_webhook_url {
-		info!("sending to discord");
-		discord::send(webhook_url, &out).await?;
-		debug!("sent to discord");
+	warn!("{} CAPs failed to fetch or parse", failed.len());
+	for item in failed {
+		warn!(guid=%item.guid, feed=%item.feed, attempts=%item.attempts, "{}", item.error);
+	}
+
+	// only alert on the first failure, as they're retried on every poll with --retry-failed
+	let new = failed
+		.iter()
+		.filter(|item| item.attempts == 1)
+		.collect::<Vec<_>>();
+	let admin = match admin {
+		Some(admin) if !new.is_empty() => admin,
+		_ => return,
+	};
+
+	let message = format!(
+		"capchat failed to fetch or parse {} CAP{}:\n{}",
+		new.len(),
+		if new.len() == 1 { "" } else { "s" },
+		new.iter()
+			.map(|item| format!("- {} (from {}): {}", item.link, item.feed, item.error))
+			.join("\n")
+	);
+
+	let route = Route {
+		name: "admin".into(),
+		..Route::default()
+	};
+	let out = Out {
+		message,
+		image: None,
+	};
+
+	debug!("alerting admin webhook about failed caps");
+	if let Err(err) = webhook::send(&route, admin, &[], &out).await {
+		error!("failed to alert admin webhook: {}", err);
+	}
+}
+
+/// Tidy up previously-posted messages for alerts that have expired.
+pub async fn tidy(route: &Route, cache: &Cache) -> Result<()> {
+	if let Some(sink) = &route.discord {
+		debug!(route=%route.name, "checking discord messages for expired alerts");
+		discord::sweep(route, sink, cache).await?;
+	}
+
+	if let Some(sink) = &route.matrix {
+		debug!(route=%route.name, "checking matrix events for expired alerts");
+		matrix::sweep(route, sink, cache).await?;
 	}
 
-	info!("all done");
 	Ok(())
 }
@@ -0,0 +1,350 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::Utc;
+use color_eyre::eyre::{eyre, Result};
+use itertools::Itertools;
+use reqwest::{
+	multipart::{Form, Part},
+	Client, RequestBuilder, StatusCode,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::time::sleep;
+use tracing::{debug, trace};
+
+use crate::{
+	cache::Progress,
+	cap::{Cap, Severity},
+	config::MastodonSink,
+	output::{headline, markup_to_plain, split_long_message, Out},
+	retry,
+};
+
+/// Maximum length of a status on a default Mastodon instance.
+const STATUS_LEN: usize = 500;
+
+/// Longest content warning to use, as it counts towards the status length.
+const SPOILER_LEN: usize = 200;
+
+/// Maximum length of a media description.
+const ALT_TEXT_LEN: usize = 1500;
+
+/// How many times to check whether an uploaded map is ready before giving up.
+const MEDIA_POLLS: usize = 10;
+
+/// Post alerts as a thread of statuses, the first with the map attached.
+///
+/// Severe and Extreme alerts are put behind a content warning made of their headlines.
+///
+/// Statuses already posted by an earlier attempt are skipped, and the thread continues from them.
+pub async fn send(sink: &MastodonSink, caps: &[Cap], out: &Out, progress: &Progress) -> Result<()> {
+	let spoiler_text = if caps.iter().any(|c| c.info().severity >= Severity::Severe) {
+		Some(
+			caps.iter()
+				.map(headline)
+				.unique()
+				.join(", ")
+				.chars()
+				.take(SPOILER_LEN)
+				.collect::<String>(),
+		)
+	} else {
+		None
+	};
+	let max_len = STATUS_LEN - spoiler_text.as_ref().map_or(0, |s| s.chars().count());
+
+	let mut image = out.image.clone();
+	let mut in_reply_to_id = None;
+	let mut rest = Some(Out {
+		message: markup_to_plain(&out.message),
+		image: None,
+	});
+	let client = Client::new();
+	let mut part = 0;
+	while let Some(out) = rest {
+		let (first, remainder) = split_long_message(out, max_len, 100);
+		rest = remainder;
+
+		// the map goes on the first status
+		let sent = Out {
+			message: first.message,
+			image: image.take(),
+		};
+		if let Some(id) = progress.sent(part, &sent)? {
+			in_reply_to_id = Some(id);
+			part += 1;
+			continue;
+		}
+
+		let mut media_ids = Vec::with_capacity(1);
+		if let Some(ref image) = sent.image {
+			media_ids.push(upload_media(sink, image, &alt_text(caps)).await?);
+		}
+
+		// lets mastodon recognise a retried status rather than posting it twice
+		let key = format!("capchat-{}", Utc::now().timestamp_nanos());
+		let status = Status {
+			status: sent.message.clone(),
+			media_ids,
+			in_reply_to_id: in_reply_to_id.take(),
+			spoiler_text: spoiler_text.clone(),
+			visibility: sink.visibility.clone(),
+		};
+
+		let body = request(sink, || {
+			Ok(client
+				.post(api_url(sink, "v1/statuses"))
+				.header("Idempotency-Key", &key)
+				.json(&status))
+		})
+		.await?;
+		in_reply_to_id = Some(
+			body.get("id")
+				.and_then(Value::as_str)
+				.map(String::from)
+				.ok_or_else(|| eyre!("mastodon did not return a status id"))?,
+		);
+		progress.record(part, &sent, in_reply_to_id.as_deref().unwrap_or_default())?;
+		part += 1;
+	}
+
+	Ok(())
+}
+
+/// Describe the map for screen readers.
+fn alt_text(caps: &[Cap]) -> String {
+	let areas = caps
+		.iter()
+		.flat_map(|c| c.info().areas.iter().map(|a| a.desc.as_str()))
+		.unique()
+		.join(", ");
+
+	format!("Map of alert areas: {}", areas)
+		.chars()
+		.take(ALT_TEXT_LEN)
+		.collect()
+}
+
+async fn upload_media(sink: &MastodonSink, image: &[u8], description: &str) -> Result<String> {
+	let client = Client::new();
+	let body = request(sink, || {
+		let part = Part::bytes(image.to_vec())
+			.file_name("map.png")
+			.mime_str("image/png")?;
+		let form = Form::new()
+			.part("file", part)
+			.text("description", description.to_string());
+		Ok(client.post(api_url(sink, "v2/media")).multipart(form))
+	})
+	.await?;
+	let id = body
+		.get("id")
+		.and_then(Value::as_str)
+		.map(String::from)
+		.ok_or_else(|| eyre!("mastodon did not return a media id"))?;
+
+	// large media are processed asynchronously, and can't be attached until they're done
+	if body.get("url").is_none_or(Value::is_null) {
+		wait_for_media(sink, &id).await?;
+	}
+
+	Ok(id)
+}
+
+async fn wait_for_media(sink: &MastodonSink, id: &str) -> Result<()> {
+	let client = Client::new();
+	for _ in 0..MEDIA_POLLS {
+		debug!(%id, "waiting for mastodon to process the map");
+		sleep(Duration::from_secs(1)).await;
+
+		let resp = client
+			.get(api_url(sink, &format!("v1/media/{}", id)))
+			.bearer_auth(&sink.access_token)
+			.send()
+			.await?;
+		let status = resp.status();
+		trace!(%status, "media status from mastodon");
+
+		match status {
+			StatusCode::OK => return Ok(()),
+			StatusCode::PARTIAL_CONTENT => continue,
+			_ => {
+				return Err(eyre!(
+					"failed to check media on mastodon: {}\n{}",
+					status,
+					resp.text().await?
+				))
+			}
+		}
+	}
+
+	Err(eyre!("mastodon took too long to process the map"))
+}
+
+fn api_url(sink: &MastodonSink, path: &str) -> String {
+	format!("{}/api/{}", sink.instance.trim_end_matches('/'), path)
+}
+
+async fn request(
+	sink: &MastodonSink,
+	make: impl Fn() -> Result<RequestBuilder>,
+) -> Result<HashMap<String, Value>> {
+	// not tracing the request itself, as it contains the token
+	let resp = retry::send("mastodon", || Ok(make()?.bearer_auth(&sink.access_token))).await?;
+	let status = resp.status();
+	trace!(%status, "response from mastodon");
+
+	let body: HashMap<String, Value> = resp.json().await?;
+	trace!(?body, "response body");
+
+	if !status.is_success() {
+		Err(eyre!(
+			"failed to send status to mastodon: {}\n{:?}",
+			status,
+			body
+		))
+	} else {
+		Ok(body)
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Status {
+	status: String,
+
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	media_ids: Vec<String>,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	in_reply_to_id: Option<String>,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	spoiler_text: Option<String>,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	visibility: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		cache::Cache,
+		testing::{alert, http_server_replying},
+	};
+
+	fn sink(url: &str) -> MastodonSink {
+		MastodonSink {
+			instance: format!("{}/", url),
+			access_token: "token".into(),
+			visibility: None,
+		}
+	}
+
+	/// Text long enough to take two statuses.
+	fn long_message() -> String {
+		"Heavy rain. ".repeat(50)
+	}
+
+	#[tokio::test]
+	async fn threads_statuses_behind_content_warning() {
+		let (url, requests) = http_server_replying(vec![
+			(200, r#"{"id":"m1","url":"https://example.org/map.png"}"#),
+			(200, r#"{"id":"1"}"#),
+			(200, r#"{"id":"2"}"#),
+		])
+		.await;
+
+		let cap = alert();
+		let caps = [cap.clone(), cap.clone()];
+		let out = Out {
+			message: long_message(),
+			image: Some(b"fake png".to_vec()),
+		};
+		let cache = Cache::temporary().unwrap();
+		let progress = cache.progress("default/mastodon", &caps);
+		send(&sink(&url), &caps, &out, &progress).await.unwrap();
+
+		let requests = requests.await.unwrap();
+		assert_eq!(requests.len(), 3);
+		assert!(requests
+			.iter()
+			.all(|r| r.header("authorization") == Some("Bearer token")));
+
+		let upload = &requests[0];
+		assert_eq!(upload.path, "/api/v2/media");
+		assert!(upload
+			.text()
+			.contains("Map of alert areas: Tararua Range\r\n"));
+
+		let first = &requests[1];
+		assert_eq!(first.path, "/api/v1/statuses");
+		assert!(first.header("idempotency-key").is_some());
+		let first = first.json();
+		assert_eq!(first["media_ids"][0], "m1");
+		assert!(first.get("in_reply_to_id").is_none());
+		// headlines are only listed once
+		assert_eq!(first["spoiler_text"], headline(&cap));
+
+		let second = requests[2].json();
+		assert!(second.get("media_ids").is_none());
+		assert_eq!(second["in_reply_to_id"], "1");
+		assert_eq!(second["spoiler_text"], headline(&cap));
+
+		let text = format!("{}{}", first["status"], second["status"]);
+		assert_eq!(text.matches("Heavy rain.").count(), 50);
+		for status in [&first, &second] {
+			let len = status["status"].as_str().unwrap().chars().count()
+				+ status["spoiler_text"].as_str().unwrap().chars().count();
+			assert!(len <= STATUS_LEN);
+		}
+	}
+
+	#[tokio::test]
+	async fn no_content_warning_below_severe() {
+		let (url, requests) = http_server_replying(vec![(200, r#"{"id":"1"}"#)]).await;
+
+		let mut cap = alert();
+		cap.infos[0].severity = Severity::Moderate;
+		let sink = MastodonSink {
+			visibility: Some("unlisted".into()),
+			..sink(&url)
+		};
+		let out = Out {
+			message: "Heavy rain.".into(),
+			image: None,
+		};
+		let cache = Cache::temporary().unwrap();
+		let caps = [cap];
+		let progress = cache.progress("default/mastodon", &caps);
+		send(&sink, &caps, &out, &progress).await.unwrap();
+
+		let status = requests.await.unwrap()[0].json();
+		assert_eq!(status["status"], "Heavy rain.");
+		assert_eq!(status["visibility"], "unlisted");
+		assert!(status.get("spoiler_text").is_none());
+		assert!(status.get("media_ids").is_none());
+	}
+
+	#[tokio::test]
+	async fn retry_continues_thread() {
+		let (url, requests) = http_server_replying(vec![(200, r#"{"id":"2"}"#)]).await;
+
+		let caps = [alert()];
+		let out = Out {
+			message: long_message(),
+			image: None,
+		};
+		let cache = Cache::temporary().unwrap();
+		let progress = cache.progress("default/mastodon", &caps);
+		let max_len = STATUS_LEN - headline(&caps[0]).chars().count();
+		let (first, _) = split_long_message(out.clone(), max_len, 100);
+		progress.record(0, &first, "1").unwrap();
+
+		send(&sink(&url), &caps, &out, &progress).await.unwrap();
+
+		let requests = requests.await.unwrap();
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].json()["in_reply_to_id"], "1");
+	}
+}
@@ -0,0 +1,623 @@
+use std::{
+	collections::HashMap,
+	fmt,
+	iter::once,
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
+use chrono::Utc;
+use color_eyre::eyre::{eyre, Result};
+use reqwest::{Client, RequestBuilder, StatusCode, Url};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{debug, error, info, trace};
+
+use crate::{
+	cache::{Cache, Posted, PostedMessage, Progress},
+	cap::{Cap, MsgType},
+	config::{MatrixSink, Route},
+	geodirs::Geodata,
+	output::{markup_to_html, markup_to_plain, render, Out},
+	retry,
+};
+
+static TXN: AtomicUsize = AtomicUsize::new(0);
+
+/// An event we were going to edit is gone, e.g. because a moderator redacted it.
+#[derive(Clone, Copy, Debug)]
+struct UnknownEvent;
+
+impl fmt::Display for UnknownEvent {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "matrix event no longer exists or was redacted")
+	}
+}
+
+impl std::error::Error for UnknownEvent {}
+
+/// Post alerts as an `m.image` event for the map followed by an `m.text` event.
+///
+/// An update to an alert that was posted alone edits the original events with `m.replace`. A
+/// cancellation strikes through the original text once every alert it was for is stale.
+///
+/// A batch that fails to be retired doesn't stop the rest from being posted, but is still reported.
+pub async fn post(
+	route: &Route,
+	sink: &MatrixSink,
+	cache: &Cache,
+	geo: &Geodata,
+	caps: &[Cap],
+	out: &Out,
+	progress: &Progress,
+) -> Result<()> {
+	let key = sink_key(route);
+
+	let mut failed = Vec::new();
+	let mut fresh = Vec::with_capacity(caps.len());
+	for cap in caps {
+		let original = match cap.supersedes.first() {
+			Some(original) if cap.is_follow_up() => original,
+			_ => {
+				fresh.push(cap.clone());
+				continue;
+			}
+		};
+
+		let (batch, mut posted) = match cache.messages_for(&key, original)? {
+			Some(found) => found,
+			None => {
+				fresh.push(cap.clone());
+				continue;
+			}
+		};
+
+		let alone = posted.alerts.iter().all(|a| a.guid == *original);
+		if cap.msg_type == MsgType::Update && alone {
+			info!(guid=%cap.guid, %original, "editing matrix events for update");
+			let out = render(once(cap.clone()).collect(), route, geo).await?;
+			match edit(sink, &posted.messages, &out).await {
+				Ok(messages) => {
+					posted.messages = messages;
+					for alert in &mut posted.alerts {
+						alert.expires = cap.info().expires;
+					}
+					cache.save_messages(&batch, &posted)?;
+				}
+				// there's nothing left to edit, so the update is posted on its own instead
+				Err(err) if err.downcast_ref::<UnknownEvent>().is_some() => {
+					info!(guid=%cap.guid, %original, "original matrix events are gone, posting update as new");
+					cache.forget_messages(&key, &batch, &posted)?;
+					fresh.push(cap.clone());
+				}
+				Err(err) => return Err(err),
+			}
+			continue;
+		}
+
+		// the follow-up is still posted, so the room sees why the original was struck through
+		if cap.msg_type == MsgType::Cancel {
+			for alert in posted.alerts.iter_mut().filter(|a| a.guid == *original) {
+				alert.stale = true;
+			}
+			if let Err(err) = retire(route, sink, cache, &batch, &posted).await {
+				error!(%batch, "failed to retire matrix events: {:?}", err);
+				failed.push(batch);
+			}
+		}
+
+		fresh.push(cap.clone());
+	}
+
+	if fresh.is_empty() {
+		return retired(failed);
+	}
+
+	let messages = if fresh.len() == caps.len() {
+		send(sink, out, progress).await?
+	} else {
+		let out = render(fresh.iter().cloned().collect(), route, geo).await?;
+		send(sink, &out, progress).await?
+	};
+
+	// reminders are one-off notices, so there's nothing to edit or retire later
+	if fresh.iter().all(|cap| cap.reminder.is_some()) {
+		return retired(failed);
+	}
+
+	cache.record_messages(&key, &fresh, messages)?;
+	retired(failed)
+}
+
+/// Strike through events for alerts that have expired.
+///
+/// A batch that fails to be retired doesn't stop the others; it's tried again on the next sweep.
+pub async fn sweep(route: &Route, sink: &MatrixSink, cache: &Cache) -> Result<()> {
+	let now = Utc::now();
+	let mut failed = Vec::new();
+	for (batch, mut posted) in cache.all_messages(&sink_key(route))? {
+		let mut changed = false;
+		for alert in posted
+			.alerts
+			.iter_mut()
+			.filter(|a| !a.stale && a.expires.is_some_and(|e| e < now))
+		{
+			debug!(guid=%alert.guid, expires=?alert.expires, "alert has expired");
+			alert.stale = true;
+			changed = true;
+		}
+
+		if changed {
+			if let Err(err) = retire(route, sink, cache, &batch, &posted).await {
+				error!(%batch, "failed to retire matrix events: {:?}", err);
+				failed.push(batch);
+			}
+		}
+	}
+
+	retired(failed)
+}
+
+fn retired(failed: Vec<String>) -> Result<()> {
+	if failed.is_empty() {
+		Ok(())
+	} else {
+		Err(eyre!(
+			"failed to retire matrix events: {}",
+			failed.join(", ")
+		))
+	}
+}
+
+/// Strike through the text of a batch of events and stop tracking it, if all of its alerts are stale.
+async fn retire(
+	route: &Route,
+	sink: &MatrixSink,
+	cache: &Cache,
+	batch: &str,
+	posted: &Posted,
+) -> Result<()> {
+	if !posted.alerts.iter().all(|a| a.stale) {
+		debug!(%batch, "some alerts in batch are still current, keeping events");
+		return cache.save_messages(batch, posted);
+	}
+
+	info!(%batch, "retiring stale matrix events");
+	for message in posted.messages.iter().filter(|m| !m.image) {
+		// an event someone already redacted is as good as retired, and would otherwise fail forever
+		if is_gone(sink, &message.id).await? {
+			debug!(%batch, id=%message.id, "matrix event is already gone");
+			continue;
+		}
+
+		let content = Content::struck(&message.content);
+		send_event(sink, &Content::replace(&message.id, content)).await?;
+	}
+
+	cache.forget_messages(&sink_key(route), batch, posted)
+}
+
+fn sink_key(route: &Route) -> String {
+	format!("{}/matrix", route.name)
+}
+
+async fn send(sink: &MatrixSink, out: &Out, progress: &Progress) -> Result<Vec<PostedMessage>> {
+	let mut messages = Vec::with_capacity(2);
+
+	if let Some(ref image) = out.image {
+		let part = Out {
+			message: String::new(),
+			image: Some(image.clone()),
+		};
+		let id = match progress.sent(0, &part)? {
+			Some(id) => id,
+			None => {
+				let content = image_content(sink, image).await?;
+				let id = send_event(sink, &content).await?;
+				progress.record(0, &part, &id)?;
+				id
+			}
+		};
+
+		messages.push(PostedMessage {
+			id,
+			content: String::new(),
+			image: true,
+		});
+	}
+
+	let part = Out {
+		message: out.message.clone(),
+		image: None,
+	};
+	let id = match progress.sent(messages.len(), &part)? {
+		Some(id) => id,
+		None => {
+			let id = send_event(sink, &Content::text(&out.message)).await?;
+			progress.record(messages.len(), &part, &id)?;
+			id
+		}
+	};
+
+	messages.push(PostedMessage {
+		id,
+		content: out.message.clone(),
+		image: false,
+	});
+
+	Ok(messages)
+}
+
+/// Replace previously-sent events with new output.
+///
+/// If there's a new map but no previous image event (or vice versa), the map is left as it was.
+/// If any of the previous events are gone, nothing is edited and this fails with `UnknownEvent`.
+async fn edit(sink: &MatrixSink, old: &[PostedMessage], out: &Out) -> Result<Vec<PostedMessage>> {
+	// edits of a redacted event are accepted, but never shown
+	for message in old {
+		if is_gone(sink, &message.id).await? {
+			return Err(UnknownEvent.into());
+		}
+	}
+
+	let mut messages = Vec::with_capacity(old.len());
+	for message in old {
+		if message.image {
+			if let Some(ref image) = out.image {
+				let content = image_content(sink, image).await?;
+				send_event(sink, &Content::replace(&message.id, content)).await?;
+			}
+			messages.push(message.clone());
+		} else {
+			let content = Content::text(&out.message);
+			send_event(sink, &Content::replace(&message.id, content)).await?;
+			messages.push(PostedMessage {
+				content: out.message.clone(),
+				..message.clone()
+			});
+		}
+	}
+
+	Ok(messages)
+}
+
+async fn image_content(sink: &MatrixSink, image: &[u8]) -> Result<Content> {
+	let url = upload_image(sink, image).await?;
+	Ok(Content {
+		msgtype: "m.image",
+		body: "map.png".into(),
+		url: Some(url),
+		info: Some(ImageInfo {
+			mimetype: "image/png",
+			size: image.len(),
+		}),
+		..Content::default()
+	})
+}
+
+async fn upload_image(sink: &MatrixSink, image: &[u8]) -> Result<String> {
+	let mut url = api_url(sink, &["_matrix", "media", "v3", "upload"])?;
+	url.query_pairs_mut().append_pair("filename", "map.png");
+
+	let client = Client::new();
+	let body = request(sink, || {
+		client
+			.post(url.clone())
+			.header("Content-Type", "image/png")
+			.body(image.to_vec())
+	})
+	.await?;
+	body.get("content_uri")
+		.and_then(Value::as_str)
+		.map(String::from)
+		.ok_or_else(|| eyre!("matrix did not return a content uri"))
+}
+
+async fn send_event(sink: &MatrixSink, content: &Content) -> Result<String> {
+	let txn = format!(
+		"capchat-{}-{}",
+		Utc::now().timestamp_nanos(),
+		TXN.fetch_add(1, Ordering::Relaxed)
+	);
+	let url = api_url(
+		sink,
+		&[
+			"_matrix",
+			"client",
+			"v3",
+			"rooms",
+			sink.room_id.as_str(),
+			"send",
+			"m.room.message",
+			txn.as_str(),
+		],
+	)?;
+
+	// the transaction id makes retries idempotent
+	let client = Client::new();
+	let body = request(sink, || client.put(url.clone()).json(content)).await?;
+	body.get("event_id")
+		.and_then(Value::as_str)
+		.map(String::from)
+		.ok_or_else(|| eyre!("matrix did not return an event id"))
+}
+
+/// Whether an event has been redacted, or can't be found in the room anymore.
+async fn is_gone(sink: &MatrixSink, event_id: &str) -> Result<bool> {
+	let url = api_url(
+		sink,
+		&[
+			"_matrix",
+			"client",
+			"v3",
+			"rooms",
+			sink.room_id.as_str(),
+			"event",
+			event_id,
+		],
+	)?;
+
+	let client = Client::new();
+	let resp = retry::send("matrix", || {
+		Ok(client.get(url.clone()).bearer_auth(&sink.access_token))
+	})
+	.await?;
+	trace!(?resp, "response from matrix");
+	let status = resp.status();
+
+	let body: HashMap<String, Value> = resp.json().await?;
+	trace!(?body, "response body");
+
+	if status == StatusCode::NOT_FOUND {
+		Ok(true)
+	} else if !status.is_success() {
+		Err(eyre!(
+			"failed to fetch event from matrix: {}\n{:?}",
+			status,
+			body
+		))
+	} else {
+		Ok(body
+			.get("unsigned")
+			.and_then(|unsigned| unsigned.get("redacted_because"))
+			.is_some())
+	}
+}
+
+fn api_url(sink: &MatrixSink, path: &[&str]) -> Result<Url> {
+	let mut url = Url::parse(&sink.homeserver)?;
+	url.path_segments_mut()
+		.map_err(|_| eyre!("invalid matrix homeserver url"))?
+		.pop_if_empty()
+		.extend(path);
+	Ok(url)
+}
+
+async fn request(
+	sink: &MatrixSink,
+	make: impl Fn() -> RequestBuilder,
+) -> Result<HashMap<String, Value>> {
+	let resp = retry::send("matrix", || Ok(make().bearer_auth(&sink.access_token))).await?;
+	trace!(?resp, "response from matrix");
+	let status = resp.status();
+
+	let body: HashMap<String, Value> = resp.json().await?;
+	trace!(?body, "response body");
+
+	if !status.is_success() {
+		Err(eyre!(
+			"failed to send message to matrix: {}\n{:?}",
+			status,
+			body
+		))
+	} else {
+		Ok(body)
+	}
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+struct Content {
+	msgtype: &'static str,
+	body: String,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	format: Option<&'static str>,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	formatted_body: Option<String>,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	url: Option<String>,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	info: Option<ImageInfo>,
+
+	#[serde(rename = "m.new_content", skip_serializing_if = "Option::is_none")]
+	new_content: Option<Box<Content>>,
+
+	#[serde(rename = "m.relates_to", skip_serializing_if = "Option::is_none")]
+	relates_to: Option<Relation>,
+}
+
+impl Content {
+	fn text(text: &str) -> Self {
+		Self {
+			msgtype: "m.text",
+			body: markup_to_plain(text),
+			format: Some("org.matrix.custom.html"),
+			formatted_body: Some(markup_to_html(text)),
+			..Self::default()
+		}
+	}
+
+	/// Text content shown struck through, for alerts that are no longer current.
+	fn struck(text: &str) -> Self {
+		Self {
+			formatted_body: Some(format!("<del>{}</del>", markup_to_html(text))),
+			..Self::text(text)
+		}
+	}
+
+	/// Wrap new content into an edit of a previous event.
+	fn replace(event_id: &str, new: Self) -> Self {
+		Self {
+			msgtype: new.msgtype,
+			body: format!("* {}", new.body),
+			format: new.format,
+			formatted_body: new
+				.formatted_body
+				.as_ref()
+				.map(|html| format!("* {}", html)),
+			url: new.url.clone(),
+			info: new.info.clone(),
+			new_content: Some(Box::new(new)),
+			relates_to: Some(Relation {
+				rel_type: "m.replace",
+				event_id: event_id.into(),
+			}),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ImageInfo {
+	mimetype: &'static str,
+	size: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Relation {
+	rel_type: &'static str,
+	event_id: String,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		output::OutputFormat,
+		testing::{alert, geodata, http_server_replying},
+	};
+
+	const REDACTED: &str = r#"{"type":"m.room.message","content":{},"unsigned":{"redacted_because":{"type":"m.room.redaction"}}}"#;
+	const NOT_FOUND: &str = r#"{"errcode":"M_NOT_FOUND","error":"Event not found"}"#;
+	const FORBIDDEN: &str = r#"{"errcode":"M_FORBIDDEN","error":"You don't have permission"}"#;
+
+	fn sink(url: &str) -> MatrixSink {
+		MatrixSink {
+			homeserver: url.into(),
+			access_token: "syt_test".into(),
+			room_id: "!room:example.org".into(),
+		}
+	}
+
+	fn route() -> Route {
+		Route {
+			format: OutputFormat::Text,
+			..Route::default()
+		}
+	}
+
+	fn message(id: &str) -> PostedMessage {
+		PostedMessage {
+			id: id.into(),
+			content: format!("event {}", id),
+			image: false,
+		}
+	}
+
+	fn follow_up(msg_type: MsgType, original: &Cap) -> Cap {
+		let mut cap = alert();
+		cap.guid = "follow-up".into();
+		cap.msg_type = msg_type;
+		cap.supersedes = vec![original.guid.clone()];
+		cap
+	}
+
+	#[tokio::test]
+	async fn update_of_redacted_event_is_posted_afresh() {
+		let (url, requests) =
+			http_server_replying(vec![(200, REDACTED), (200, r#"{"event_id":"$2"}"#)]).await;
+		let (route, sink, geo) = (route(), sink(&url), geodata());
+		let cache = Cache::temporary().unwrap();
+		let key = sink_key(&route);
+		let original = alert();
+		cache
+			.record_messages(&key, std::slice::from_ref(&original), vec![message("$1")])
+			.unwrap();
+
+		let caps = [follow_up(MsgType::Update, &original)];
+		let out = render(caps.iter().cloned().collect(), &route, &geo)
+			.await
+			.unwrap();
+		let progress = cache.progress(&key, &caps);
+		post(&route, &sink, &cache, &geo, &caps, &out, &progress)
+			.await
+			.unwrap();
+
+		let requests = requests.await.unwrap();
+		assert_eq!(requests[0].method, "GET");
+		assert!(requests[0].path.ends_with("/event/$1"));
+		assert_eq!(requests[1].method, "PUT");
+		assert!(requests[1].json().get("m.relates_to").is_none());
+
+		let (batch, _) = cache.messages_for(&key, &original.guid).unwrap().unwrap();
+		assert_eq!(batch, format!("{}/$2", key));
+		assert_eq!(cache.all_messages(&key).unwrap().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn cancellation_is_posted_even_if_retiring_fails() {
+		let (url, requests) =
+			http_server_replying(vec![(403, FORBIDDEN), (200, r#"{"event_id":"$2"}"#)]).await;
+		let (route, sink, geo) = (route(), sink(&url), geodata());
+		let cache = Cache::temporary().unwrap();
+		let key = sink_key(&route);
+		let original = alert();
+		cache
+			.record_messages(&key, std::slice::from_ref(&original), vec![message("$1")])
+			.unwrap();
+
+		let caps = [follow_up(MsgType::Cancel, &original)];
+		let out = render(caps.iter().cloned().collect(), &route, &geo)
+			.await
+			.unwrap();
+		let progress = cache.progress(&key, &caps);
+		let err = post(&route, &sink, &cache, &geo, &caps, &out, &progress)
+			.await
+			.unwrap_err();
+		assert!(err.to_string().contains(&format!("{}/$1", key)));
+
+		let requests = requests.await.unwrap();
+		assert_eq!(requests[1].method, "PUT");
+		assert_eq!(progress.sent(0, &out).unwrap().as_deref(), Some("$2"));
+	}
+
+	#[tokio::test]
+	async fn sweep_skips_missing_events_and_carries_on_past_a_failed_batch() {
+		let (url, requests) = http_server_replying(vec![(403, FORBIDDEN), (404, NOT_FOUND)]).await;
+		let (route, sink) = (route(), sink(&url));
+		let cache = Cache::temporary().unwrap();
+		let key = sink_key(&route);
+		// the fixture alert expired long ago
+		let mut other = alert();
+		other.guid = "other".into();
+		cache
+			.record_messages(&key, &[alert()], vec![message("$1")])
+			.unwrap();
+		cache
+			.record_messages(&key, &[other], vec![message("$2")])
+			.unwrap();
+
+		let err = sweep(&route, &sink, &cache).await.unwrap_err();
+		assert!(err.to_string().contains(&format!("{}/$1", key)));
+
+		// nothing is struck through, as the only event left to strike is gone
+		let requests = requests.await.unwrap();
+		assert_eq!(requests.len(), 2);
+		assert!(requests.iter().all(|r| r.method == "GET"));
+
+		let left = cache.all_messages(&key).unwrap();
+		assert_eq!(left.len(), 1);
+		assert_eq!(left[0].0, format!("{}/$1", key));
+	}
+}
@@ -1,13 +1,21 @@
-use std::str::FromStr;
+use std::{collections::HashSet, str::FromStr};
 
 use color_eyre::eyre::Result;
 use itertools::Itertools;
 use tracing::debug;
 
 pub use map::text_with_map;
-pub use text::text;
+pub use markup::{markup_to_html, markup_to_plain, markup_to_telegram};
+pub use text::{text, when_where};
+
+use crate::{
+	cap::{Cap, MsgType, Reminder, Severity},
+	config::Route,
+	geodirs::Geodata,
+};
 
 mod map;
+mod markup;
 mod text;
 
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
@@ -36,6 +44,40 @@ impl FromStr for OutputFormat {
 	}
 }
 
+pub async fn render(caps: HashSet<Cap>, route: &Route, geo: &Geodata) -> Result<Out> {
+	match route.format {
+		OutputFormat::Json => Ok(Out {
+			message: serde_json::to_string(&caps)?,
+			..Out::default()
+		}),
+		OutputFormat::Text => text(caps),
+		OutputFormat::Map => text_with_map(caps, route, geo).await,
+	}
+}
+
+/// The `ColourCode` parameter of the alert, if any.
+pub fn colour_code(cap: &Cap) -> Option<&str> {
+	cap.info().parameters.get("ColourCode").map(String::as_str)
+}
+
+/// Hex colour for the alert, from its `ColourCode` or else its severity.
+pub fn colour_hex(cap: &Cap) -> &'static str {
+	match colour_code(cap).map(str::to_lowercase).as_deref() {
+		Some("blue") => "#1e88e5",
+		Some("green") => "#43a047",
+		Some("yellow") => "#fdd835",
+		Some("orange") => "#fb8c00",
+		Some("red") => "#e53935",
+		Some("purple") => "#8e24aa",
+		_ => match cap.info().severity {
+			Severity::Minor => "#1e88e5",
+			Severity::Moderate => "#fdd835",
+			Severity::Severe => "#fb8c00",
+			Severity::Extreme => "#e53935",
+		},
+	}
+}
+
 pub fn colour_code_emoji(c: &str) -> Option<char> {
 	match c.to_lowercase().as_str() {
 		"blue" => Some('🔵'),
@@ -48,6 +90,33 @@ pub fn colour_code_emoji(c: &str) -> Option<char> {
 	}
 }
 
+/// Uppercased headline, prefixed if the CAP follows up on an alert that was already posted,
+/// if it's a reminder, and if it's not a real alert (e.g. `EXERCISE:`).
+pub fn headline(cap: &Cap) -> String {
+	let prefix = match (cap.msg_type, cap.reminder) {
+		(_, Some(Reminder::Onset)) => "NOW IN EFFECT: ",
+		(_, Some(Reminder::Expiry)) => "EXPIRED: ",
+		(MsgType::Update, _) if cap.is_follow_up() => "UPDATED: ",
+		(MsgType::Cancel, _) if cap.is_follow_up() => "CANCELLED: ",
+		_ => "",
+	};
+
+	let status = if cap.status.is_empty() || cap.status.eq_ignore_ascii_case("actual") {
+		String::new()
+	} else {
+		format!("{}: ", cap.status.to_uppercase())
+	};
+
+	// cancellations without an info block have no headline, but may have a note
+	let about = match (cap.info().headline.as_str(), &cap.note) {
+		("", Some(note)) => note.as_str(),
+		("", None) => "alert",
+		(headline, _) => headline,
+	};
+
+	format!("{}{}{}", status, prefix, about.to_uppercase())
+}
+
 pub fn split_long_message(out: Out, max_len: usize, min_len: usize) -> (Out, Option<Out>) {
 	let len = out.message.chars().count();
 	if len <= max_len {
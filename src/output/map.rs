@@ -6,34 +6,22 @@ use geo::prelude::{BoundingRect, Contains};
 use geo::{Geometry, MultiPolygon};
 use geo_booleanop::boolean::BooleanOp;
 use geozero::ToSvg;
-use itertools::Itertools;
 use tiny_skia::Pixmap;
 use tracing::{debug, trace};
 use usvg::{FitTo, Options, Size, Tree};
 
 use super::{text, Out};
 use crate::cap::Cap;
-use crate::geodirs::load_polygons;
-use crate::Args;
-
-pub async fn text_with_map(caps: HashSet<Cap>, args: &Args) -> Result<Out> {
-	debug!("loading boundaries");
-	let boundaries = MultiPolygon(
-		load_polygons(&args.boundaries)
-			.await?
-			.0
-			.into_iter()
-			.fold1(|a, b| a.union(&b).concave_hull(2.0))
-			.map(|p| vec![p])
-			.unwrap_or_default(),
-	);
+use crate::config::Route;
+use crate::geodirs::Geodata;
+
+pub async fn text_with_map(caps: HashSet<Cap>, route: &Route, geo: &Geodata) -> Result<Out> {
+	let boundaries = &geo.hull;
 
 	let mut areas = caps
 		.iter()
-		.map(|c| c.info.areas.clone())
-		.flatten()
-		.map(|a| a.polygons)
-		.flatten()
+		.flat_map(|c| c.info().areas.clone())
+		.flat_map(|a| a.polygons)
 		.collect::<MultiPolygon<f64>>();
 
 	let bbox = boundaries
@@ -44,7 +32,7 @@ pub async fn text_with_map(caps: HashSet<Cap>, args: &Args) -> Result<Out> {
 
 	if !boundaries.0.is_empty() && !boundaries.contains(&areas) {
 		debug!("cropping areas to boundaries");
-		areas = areas.intersection(&boundaries);
+		areas = areas.intersection(boundaries);
 	}
 
 	#[cfg(debug_assertions)]
@@ -58,12 +46,11 @@ pub async fn text_with_map(caps: HashSet<Cap>, args: &Args) -> Result<Out> {
 		opacity: 0.5,
 	}];
 
-	debug!("loading outlines");
-	let mut outlines = load_polygons(&args.outlines).await?;
+	let mut outlines = geo.outlines.clone();
 	if !outlines.0.is_empty() {
 		if !boundaries.0.is_empty() && !boundaries.contains(&outlines) {
 			debug!("cropping outlines to boundaries");
-			outlines = outlines.intersection(&boundaries);
+			outlines = outlines.intersection(boundaries);
 		}
 
 		#[cfg(debug_assertions)]
@@ -98,14 +85,14 @@ pub async fn text_with_map(caps: HashSet<Cap>, args: &Args) -> Result<Out> {
 	trace!(svg=%svg.root().traverse().map(|n| format!("{:?}", n)).collect::<Vec<_>>().join("\n"), "usvg tree");
 
 	debug!("rendering");
-	let mut pix = Pixmap::new(args.image_width, args.image_height)
+	let mut pix = Pixmap::new(route.image_width, route.image_height)
 		.ok_or_else(|| eyre!("Cannot create zero-sized image"))?;
 	trace!(?pix, "created image buffer");
 
 	debug!("rendering svg to image buffer");
 	resvg::render(
 		&svg,
-		FitTo::Size(args.image_width, args.image_height),
+		FitTo::Size(route.image_width, route.image_height),
 		pix.as_mut(),
 	)
 	.ok_or_else(|| eyre!("Failed to render image"))?;
@@ -0,0 +1,108 @@
+use itertools::Itertools;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Token {
+	Char(char),
+	Open(char),
+	Close(char),
+}
+
+/// Convert the `*bold*` and `_italics_` markup of the text output to HTML.
+pub fn markup_to_html(text: &str) -> String {
+	text.lines()
+		.map(|line| {
+			tokens(line)
+				.into_iter()
+				.map(|token| match token {
+					Token::Open('*') => "<strong>".into(),
+					Token::Close('*') => "</strong>".into(),
+					Token::Open(_) => "<em>".into(),
+					Token::Close(_) => "</em>".into(),
+					Token::Char('&') => "&amp;".into(),
+					Token::Char('<') => "&lt;".into(),
+					Token::Char('>') => "&gt;".into(),
+					Token::Char('"') => "&quot;".into(),
+					Token::Char(c) => c.to_string(),
+				})
+				.collect::<String>()
+		})
+		.join("<br>\n")
+}
+
+/// Convert the markup of the text output to Telegram's MarkdownV2, escaping everything else.
+pub fn markup_to_telegram(text: &str) -> String {
+	// https://core.telegram.org/bots/api#markdownv2-style
+	const SPECIAL: &str = "_*[]()~`>#+-=|{}.!\\";
+
+	text.lines()
+		.map(|line| {
+			tokens(line)
+				.into_iter()
+				.map(|token| match token {
+					Token::Open(c) | Token::Close(c) => c.to_string(),
+					Token::Char(c) if SPECIAL.contains(c) => format!("\\{}", c),
+					Token::Char(c) => c.to_string(),
+				})
+				.collect::<String>()
+		})
+		.join("\n")
+}
+
+/// Strip the markup of the text output, for services that only take plain text.
+pub fn markup_to_plain(text: &str) -> String {
+	text.lines()
+		.map(|line| {
+			tokens(line)
+				.into_iter()
+				.filter_map(|token| match token {
+					Token::Char(c) => Some(c),
+					Token::Open(_) | Token::Close(_) => None,
+				})
+				.collect::<String>()
+		})
+		.join("\n")
+}
+
+/// Find the markers in a line of markup.
+///
+/// Markers only count at word boundaries, so underscores in URLs and the like are left alone.
+/// Unclosed markers are closed at the end of the line.
+fn tokens(line: &str) -> Vec<Token> {
+	let chars = line.chars().collect::<Vec<_>>();
+	let mut tokens = Vec::with_capacity(chars.len());
+	let mut open: Option<char> = None;
+
+	for (i, &c) in chars.iter().enumerate() {
+		let prev = i.checked_sub(1).map(|j| chars[j]);
+		let next = chars.get(i + 1).copied();
+
+		if c == '*' || c == '_' {
+			if open == Some(c)
+				&& prev.is_some_and(|p| !p.is_whitespace())
+				&& next.is_none_or(|n| !n.is_alphanumeric())
+			{
+				tokens.push(Token::Close(c));
+				open = None;
+				continue;
+			}
+
+			if open.is_none()
+				&& prev.is_none_or(char::is_whitespace)
+				&& next.is_some_and(|n| !n.is_whitespace())
+				&& chars[i + 1..].contains(&c)
+			{
+				tokens.push(Token::Open(c));
+				open = Some(c);
+				continue;
+			}
+		}
+
+		tokens.push(Token::Char(c));
+	}
+
+	if let Some(c) = open {
+		tokens.push(Token::Close(c));
+	}
+
+	tokens
+}
@@ -1,41 +1,32 @@
 use std::collections::HashSet;
 
-use chrono::Local;
+use chrono::{DateTime, Local, Utc};
 use color_eyre::eyre::Result;
 use itertools::Itertools;
 
-use super::{colour_code_emoji, Out};
-use crate::cap::Cap;
+use super::{colour_code, colour_code_emoji, headline, Out};
+use crate::{cap::Cap, signature::Verification};
 
 pub fn text(caps: HashSet<Cap>) -> Result<Out> {
 	let mut message = String::with_capacity(caps.len() * 512);
 
 	let headlines = caps
 		.iter()
-		.sorted_by_key(|c| &c.info.headline)
-		.group_by(|c| &c.info.headline);
+		.sorted_by_key(|c| headline(c))
+		.group_by(|c| headline(c));
 
 	for (headline, caps) in &headlines {
 		message.extend(
 			format!(
 				"{}\n\n{}\n",
-				headline.to_uppercase(),
+				headline,
 				caps.map(|c| {
 					format!(
-						"{}  *[{}]*  {} hours from _{}_ to _{}_\n\n{}\n\n",
-						c.info
-							.parameters
-							.get("ColourCode")
-							.and_then(|c| colour_code_emoji(c.as_str()))
-							.unwrap_or(' '),
-						c.info.areas.iter().map(|a| &a.desc).join(", "),
-						c.info
-							.expires
-							.signed_duration_since(c.info.onset)
-							.num_hours(),
-						c.info.onset.with_timezone(&Local).format("%I:%M%P %A"),
-						c.info.expires.with_timezone(&Local).format("%I:%M%P %A"),
-						c.info.description,
+						"{}  {}{}\n\n{}\n\n",
+						colour_code(c).and_then(colour_code_emoji).unwrap_or(' '),
+						when_where(c),
+						affects(c),
+						c.info().description,
 					)
 					.trim_start()
 					.to_string()
@@ -51,3 +42,45 @@ pub fn text(caps: HashSet<Cap>) -> Result<Out> {
 		image: None,
 	})
 }
+
+/// Which of our boundaries an alert affects, on its own line.
+fn affects(c: &Cap) -> String {
+	if c.affects.is_empty() {
+		String::new()
+	} else {
+		format!("\nAffects: {}", c.affects.join(", "))
+	}
+}
+
+/// Areas and timing of an alert, marked up with `*bold*` and `_italics_`.
+pub fn when_where(c: &Cap) -> String {
+	let areas = c.info().areas.iter().map(|a| &a.desc).join(", ");
+	let time = |t: DateTime<Utc>| t.with_timezone(&Local).format("%I:%M%P %A");
+
+	let when_where = match (c.info().onset, c.info().expires) {
+		// a cancellation without an info block has neither
+		_ if c.infos.is_empty() => String::new(),
+		(Some(onset), Some(expires)) => format!(
+			"*[{}]*  {} hours from _{}_ to _{}_",
+			areas,
+			expires.signed_duration_since(onset).num_hours(),
+			time(onset),
+			time(expires),
+		),
+		(Some(onset), None) => {
+			format!("*[{}]*  from _{}_ until further notice", areas, time(onset))
+		}
+		(None, Some(expires)) => format!("*[{}]*  until _{}_", areas, time(expires)),
+		(None, None) => format!("*[{}]*  until further notice", areas),
+	};
+
+	match c.verification {
+		Some(Verification::Verified { .. }) => format!("{}  ✅ _verified sender_", when_where)
+			.trim_start()
+			.into(),
+		Some(_) => format!("{}  ⚠️ _unverified sender_", when_where)
+			.trim_start()
+			.into(),
+		None => when_where,
+	}
+}
@@ -0,0 +1,175 @@
+use std::cmp::Reverse;
+
+use color_eyre::eyre::{eyre, Result};
+use itertools::Itertools;
+use reqwest::Client;
+use tracing::trace;
+
+use crate::{
+	cap::{Cap, Severity},
+	config::PushSink,
+	output::{colour_code, colour_code_emoji, headline, Out},
+	retry,
+};
+
+/// Send alerts as a push notification through an ntfy-compatible server.
+///
+/// The notification has the headlines as title, the areas as body, and the map attached.
+/// Its priority is that of the most severe alert, and tapping it opens the alert's web page.
+pub async fn send(sink: &PushSink, caps: &[Cap], out: &Out) -> Result<()> {
+	let title = caps.iter().map(headline).unique().join(", ");
+	let body = caps.iter().map(areas).join("\n");
+	let severity = caps
+		.iter()
+		.map(|c| c.info().severity)
+		.max()
+		.unwrap_or(Severity::Minor);
+
+	let click = caps
+		.iter()
+		.sorted_by_key(|c| Reverse(c.info().severity))
+		.find_map(|c| c.info().web.as_ref());
+
+	let client = Client::new();
+	// not tracing the request itself, as it may contain the token
+	let resp = retry::send("push", || {
+		let mut req = client
+			.post(&sink.url)
+			.header("Title", encode_header(&title))
+			.header("Priority", priority(severity))
+			.header("Tags", tag(severity));
+
+		if let Some(web) = click {
+			req = req.header("Click", web.as_str());
+		}
+
+		if let Some(token) = &sink.token {
+			req = req.bearer_auth(token);
+		}
+
+		// with an attachment the body is the file, so the message goes in a header instead
+		Ok(if let Some(ref image) = out.image {
+			req.header("Message", encode_header(&body))
+				.header("Filename", "map.png")
+				.body(image.clone())
+		} else {
+			req.body(body.clone())
+		})
+	})
+	.await?;
+	let status = resp.status();
+	trace!(%status, "response from push server");
+
+	let body = resp.text().await?;
+	trace!(%body, "response body");
+
+	if !status.is_success() {
+		Err(eyre!(
+			"failed to send push notification: {}\n{}",
+			status,
+			body
+		))
+	} else {
+		Ok(())
+	}
+}
+
+/// Area list for one alert, prefixed with its colour code if it has one.
+fn areas(cap: &Cap) -> String {
+	let areas = cap.info().areas.iter().map(|a| &a.desc).join(", ");
+	match colour_code(cap).and_then(colour_code_emoji) {
+		Some(emoji) => format!("{} {}", emoji, areas),
+		None => areas,
+	}
+}
+
+/// Extreme alerts are sent as urgent, which bypasses do-not-disturb on most clients.
+fn priority(severity: Severity) -> &'static str {
+	match severity {
+		Severity::Extreme => "urgent",
+		Severity::Severe => "high",
+		Severity::Moderate => "default",
+		Severity::Minor => "low",
+	}
+}
+
+/// Emoji shortcode shown next to the title.
+fn tag(severity: Severity) -> &'static str {
+	match severity {
+		Severity::Extreme => "rotating_light",
+		Severity::Severe => "warning",
+		Severity::Moderate | Severity::Minor => "information_source",
+	}
+}
+
+/// Header values must be plain ASCII on one line, so anything else is sent RFC 2047-encoded.
+fn encode_header(value: &str) -> String {
+	if value.is_ascii() && !value.contains(|c: char| c.is_ascii_control()) {
+		value.into()
+	} else {
+		format!("=?UTF-8?B?{}?=", base64::encode(value))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::{alert, http_server};
+
+	#[tokio::test]
+	async fn posts_ntfy_headers() {
+		let (url, requests) = http_server(1, r#"{"id":"x"}"#).await;
+		let sink = PushSink {
+			url: format!("{}/alerts", url),
+			token: Some("tk_test".into()),
+		};
+
+		let cap = alert();
+		send(&sink, std::slice::from_ref(&cap), &Out::default())
+			.await
+			.unwrap();
+
+		let requests = requests.await.unwrap();
+		let req = &requests[0];
+		assert_eq!(req.method, "POST");
+		assert_eq!(req.path, "/alerts");
+		assert_eq!(req.header("title"), Some(headline(&cap).as_str()));
+		assert_eq!(req.header("priority"), Some("high"));
+		assert_eq!(req.header("tags"), Some("warning"));
+		assert_eq!(req.header("click"), cap.info().web.as_deref());
+		assert_eq!(req.header("authorization"), Some("Bearer tk_test"));
+		assert_eq!(req.header("filename"), None);
+		assert_eq!(req.text(), areas(&cap));
+		assert!(req.text().ends_with("Tararua Range"));
+	}
+
+	#[tokio::test]
+	async fn attaches_map() {
+		let (url, requests) = http_server(1, r#"{"id":"x"}"#).await;
+		let sink = PushSink { url, token: None };
+
+		let cap = alert();
+		let out = Out {
+			message: String::new(),
+			image: Some(b"fake png".to_vec()),
+		};
+		send(&sink, std::slice::from_ref(&cap), &out).await.unwrap();
+
+		let requests = requests.await.unwrap();
+		let req = &requests[0];
+		assert_eq!(req.header("authorization"), None);
+		assert_eq!(req.header("filename"), Some("map.png"));
+		assert_eq!(
+			req.header("message"),
+			Some(encode_header(&areas(&cap)).as_str())
+		);
+		assert_eq!(req.body, b"fake png");
+	}
+
+	#[test]
+	fn encodes_non_ascii_headers() {
+		assert_eq!(encode_header("Heavy Rain"), "Heavy Rain");
+		assert_eq!(encode_header("Pluie é"), "=?UTF-8?B?UGx1aWUgw6k=?=");
+		assert_eq!(encode_header("a\nb"), "=?UTF-8?B?YQpi?=");
+	}
+}
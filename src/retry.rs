@@ -0,0 +1,209 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::Result;
+use reqwest::{header::HeaderMap, RequestBuilder, Response, StatusCode};
+use serde_json::Value;
+use tokio::time::sleep;
+use tracing::{trace, warn};
+
+/// How many times to try a request before giving up.
+const ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry, doubled for each one after.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest we'll wait before retrying, even if asked to wait longer.
+///
+/// If a service wants us to back off for longer than this, the request fails instead, and the
+/// alert is retried for that sink on a later run.
+const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Send a request, retrying with exponential backoff on server errors and rate limits.
+///
+/// The request is built anew for each attempt, as multipart bodies can't be cloned. Once out of
+/// attempts, the last response is returned as-is for the caller to report.
+///
+/// Requests that couldn't connect are always retried, but those that timed out may have gone
+/// through, so they're only retried if their method is idempotent.
+pub async fn send<F>(service: &str, make: F) -> Result<Response>
+where
+	F: Fn() -> Result<RequestBuilder>,
+{
+	let mut attempt = 1;
+	loop {
+		let req = make()?;
+		let idempotent = req
+			.try_clone()
+			.and_then(|req| req.build().ok())
+			.is_some_and(|req| req.method().is_idempotent());
+
+		let resp = match req.send().await {
+			Ok(resp) => resp,
+			Err(err)
+				if attempt < ATTEMPTS && (err.is_connect() || (err.is_timeout() && idempotent)) =>
+			{
+				let delay = backoff(attempt);
+				warn!(%service, %attempt, ?delay, "request failed, retrying: {}", err);
+				sleep(delay).await;
+				attempt += 1;
+				continue;
+			}
+			Err(err) => return Err(err.into()),
+		};
+
+		if attempt >= ATTEMPTS {
+			return Ok(resp);
+		}
+
+		match retry_delay(&resp, attempt) {
+			Some(delay) => {
+				warn!(%service, status=%resp.status(), %attempt, ?delay, "request was rate limited or failed, retrying");
+				sleep(delay).await;
+				attempt += 1;
+			}
+			None => return Ok(resp),
+		}
+	}
+}
+
+fn backoff(attempt: u32) -> Duration {
+	(BASE_DELAY * 2_u32.saturating_pow(attempt - 1)).min(MAX_DELAY)
+}
+
+/// Whether and how long to wait before retrying, given a response.
+fn retry_delay(resp: &Response, attempt: u32) -> Option<Duration> {
+	let status = resp.status();
+	let hint = wait_hint(resp.headers());
+	trace!(%status, ?hint, "checking whether to retry");
+
+	// facebook signals throttling with a 4xx error and its usage headers rather than a 429
+	let throttled = status == StatusCode::TOO_MANY_REQUESTS
+		|| (status.is_client_error()
+			&& regain_access(resp.headers()).is_some_and(|d| d > Duration::ZERO));
+	if !throttled && !status.is_server_error() {
+		return None;
+	}
+
+	match hint {
+		Some(hint) if hint > MAX_DELAY => {
+			warn!(
+				?hint,
+				"asked to wait longer than we're willing to, giving up"
+			);
+			None
+		}
+		Some(hint) => Some(hint),
+		None => Some(backoff(attempt)),
+	}
+}
+
+/// How long the service asked us to wait, from whichever rate-limit headers it sent.
+///
+/// - `Retry-After`, in seconds or as a date;
+/// - Discord's `X-RateLimit-Reset-After`, in (fractional) seconds;
+/// - Facebook's `X-Business-Use-Case-Usage`, whose `estimated_time_to_regain_access` is in minutes.
+fn wait_hint(headers: &HeaderMap) -> Option<Duration> {
+	let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+	let retry_after = header("retry-after").and_then(|v| {
+		v.trim().parse::<f64>().ok().map(secs).or_else(|| {
+			DateTime::parse_from_rfc2822(v).ok().and_then(|d| {
+				d.with_timezone(&Utc)
+					.signed_duration_since(Utc::now())
+					.to_std()
+					.ok()
+			})
+		})
+	});
+
+	let reset_after = header("x-ratelimit-reset-after")
+		.and_then(|v| v.trim().parse::<f64>().ok())
+		.map(secs);
+
+	[retry_after, reset_after, regain_access(headers)]
+		.iter()
+		.flatten()
+		.max()
+		.copied()
+}
+
+fn regain_access(headers: &HeaderMap) -> Option<Duration> {
+	let usage: Value =
+		serde_json::from_str(headers.get("x-business-use-case-usage")?.to_str().ok()?).ok()?;
+
+	usage
+		.as_object()?
+		.values()
+		.filter_map(Value::as_array)
+		.flatten()
+		.filter_map(|u| u.get("estimated_time_to_regain_access")?.as_f64())
+		.map(|minutes| secs(minutes * 60.0))
+		.max()
+}
+
+fn secs(s: f64) -> Duration {
+	// clamped as silly values would panic, and anything over a day is as good as forever
+	Duration::from_secs_f64(s.clamp(0.0, 86400.0))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Instant;
+
+	use reqwest::Client;
+	use tokio::{io::AsyncWriteExt, net::TcpListener, task::JoinHandle};
+
+	use super::*;
+
+	/// Accept connections on a local port, leaving the first unanswered and answering the rest.
+	async fn stalling_server() -> (String, JoinHandle<()>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}", listener.local_addr().unwrap());
+		let handle = tokio::spawn(async move {
+			// kept open, so the client waits for a response until it times out
+			let _stalled = listener.accept().await.unwrap();
+			loop {
+				let (mut socket, _) = listener.accept().await.unwrap();
+				socket
+					.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+					.await
+					.unwrap();
+			}
+		});
+		(url, handle)
+	}
+
+	fn client() -> Client {
+		Client::builder()
+			.timeout(Duration::from_millis(200))
+			.build()
+			.unwrap()
+	}
+
+	#[tokio::test]
+	async fn timed_out_post_is_not_retried() {
+		let (url, server) = stalling_server().await;
+		let client = client();
+
+		let start = Instant::now();
+		let err = send("test", || Ok(client.post(&url).body("once")))
+			.await
+			.unwrap_err();
+		assert!(err.downcast_ref::<reqwest::Error>().unwrap().is_timeout());
+		assert!(start.elapsed() < BASE_DELAY);
+		server.abort();
+	}
+
+	#[tokio::test]
+	async fn timed_out_put_is_retried() {
+		let (url, server) = stalling_server().await;
+		let client = client();
+
+		let resp = send("test", || Ok(client.put(&url).body("again")))
+			.await
+			.unwrap();
+		assert_eq!(resp.status(), StatusCode::OK);
+		server.abort();
+	}
+}
@@ -0,0 +1,751 @@
+use std::{collections::BTreeMap, fmt, path::Path, str::FromStr};
+
+use color_eyre::eyre::{eyre, Result};
+use rsa::{
+	pkcs1::FromRsaPublicKey, pkcs8::FromPublicKey, Hash, PaddingScheme, PublicKey, RsaPublicKey,
+};
+use serde::Serialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use tokio::fs::read_to_string;
+use tracing::{debug, info, trace, warn};
+use xml::{
+	attribute::OwnedAttribute,
+	name::OwnedName,
+	namespace::Namespace,
+	reader::{ParserConfig, XmlEvent},
+};
+
+const DSIG: &str = "http://www.w3.org/2000/09/xmldsig#";
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const RSA_SHA1: &str = "http://www.w3.org/2000/09/xmldsig#rsa-sha1";
+const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const SHA1: &str = "http://www.w3.org/2000/09/xmldsig#sha1";
+const SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+
+/// What to do with alerts that aren't signed by a trusted key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignaturePolicy {
+	/// Don't check signatures at all.
+	Ignore,
+
+	/// Log a warning, but post them as usual.
+	Accept,
+
+	/// Post them, marked as unverified.
+	Flag,
+
+	/// Don't post them.
+	Drop,
+}
+
+impl FromStr for SignaturePolicy {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_lowercase().as_str() {
+			"ignore" => Ok(Self::Ignore),
+			"accept" => Ok(Self::Accept),
+			"flag" => Ok(Self::Flag),
+			"drop" => Ok(Self::Drop),
+			_ => Err(format!("unknown signature policy: {}", s)),
+		}
+	}
+}
+
+/// Outcome of checking a CAP's signature.
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Verification {
+	/// Signed by the trusted key of that name.
+	Verified {
+		signer: String,
+	},
+	Unsigned,
+	Invalid {
+		reason: String,
+	},
+}
+
+impl fmt::Display for Verification {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Verified { signer } => write!(f, "signed by {}", signer),
+			Self::Unsigned => write!(f, "unsigned"),
+			Self::Invalid { reason } => write!(f, "not validly signed: {}", reason),
+		}
+	}
+}
+
+/// Checks enveloped XML-DSig signatures on CAPs against a set of trusted keys.
+///
+/// This supports the profile CAP 1.2 asks senders to use: a signature over the whole alert, with
+/// the enveloped-signature transform and exclusive canonicalisation, and RSA with SHA-256 (or SHA-1).
+#[derive(Clone, Debug)]
+pub struct Verifier {
+	pub policy: SignaturePolicy,
+	keys: Vec<(String, RsaPublicKey)>,
+}
+
+impl Verifier {
+	/// Load trusted keys from the PEM files in a folder, unless the policy is to ignore signatures.
+	///
+	/// Files may hold X.509 certificates or RSA public keys. Keys are named after their file, which
+	/// must be the `<sender>` of the alerts they sign, e.g. `alerts@metservice.com.pem`.
+	pub async fn load(policy: SignaturePolicy, path: &Path) -> Result<Option<Self>> {
+		if policy == SignaturePolicy::Ignore {
+			return Ok(None);
+		}
+
+		let mut keys = Vec::new();
+		for entry in glob::glob(path.join("*.pem").display().to_string().as_str())? {
+			let file = entry?;
+			let name = file.file_stem().map_or_else(
+				|| file.display().to_string(),
+				|s| s.to_string_lossy().into(),
+			);
+			debug!(?file, "reading trusted keys");
+
+			let pem = read_to_string(&file).await?;
+			keys.extend(
+				parse_keys(&pem)
+					.map_err(|err| eyre!("invalid trusted key in {}: {}", file.display(), err))?
+					.into_iter()
+					.map(|key| (name.clone(), key)),
+			);
+		}
+
+		if keys.is_empty() {
+			warn!(?path, "no trusted keys found, no alert will verify");
+		}
+
+		info!(keys=%keys.len(), ?policy, "loaded trusted keys");
+		Ok(Some(Self { policy, keys }))
+	}
+
+	/// Check a CAP's signature and apply the policy.
+	///
+	/// Returns an error if the alert should be dropped, and otherwise what to record on the alert.
+	pub fn check(&self, guid: &str, xml: &str) -> Result<Option<Verification>> {
+		let verification = self.verify(xml);
+		match (&verification, self.policy) {
+			(Verification::Verified { signer }, _) => {
+				debug!(%guid, %signer, "verified CAP signature");
+				Ok(Some(verification))
+			}
+			(_, SignaturePolicy::Drop) => Err(eyre!("CAP {} is {}", guid, verification)),
+			(_, SignaturePolicy::Flag) => {
+				warn!(%guid, "CAP is {}, flagging it", verification);
+				Ok(Some(verification))
+			}
+			_ => {
+				warn!(%guid, "CAP is {}, accepting it anyway", verification);
+				Ok(None)
+			}
+		}
+	}
+
+	pub fn verify(&self, xml: &str) -> Verification {
+		match self.signer(xml) {
+			Ok(Some(signer)) => Verification::Verified { signer },
+			Ok(None) => Verification::Unsigned,
+			Err(reason) => Verification::Invalid { reason },
+		}
+	}
+
+	/// Name of the trusted key that signed the document, if it's signed.
+	///
+	/// Only the keys named after the alert's `<sender>` are tried, so a sender that's trusted can't
+	/// vouch for alerts claiming to be from another.
+	fn signer(&self, xml: &str) -> Result<Option<String>, String> {
+		let root = parse(xml)?;
+		let signature = match root.elements().find(|e| e.is(DSIG, "Signature")) {
+			Some(signature) => signature,
+			None => return Ok(None),
+		};
+
+		let sender = root
+			.elements()
+			.find(|e| e.name.local_name == "sender")
+			.map(|e| e.text().trim().to_string())
+			.ok_or("missing sender")?;
+		let keys = self
+			.keys
+			.iter()
+			.filter(|(name, _)| name.eq_ignore_ascii_case(&sender))
+			.collect::<Vec<_>>();
+		if keys.is_empty() {
+			return Err(format!("no trusted key for sender {}", sender));
+		}
+
+		let signed_info = signature
+			.child(DSIG, "SignedInfo")
+			.ok_or("missing SignedInfo")?;
+
+		let c14n = signed_info
+			.child(DSIG, "CanonicalizationMethod")
+			.ok_or("missing CanonicalizationMethod")?;
+		if c14n.attr("Algorithm") != Some(EXC_C14N) {
+			return Err(format!(
+				"unsupported canonicalization method: {}",
+				c14n.attr("Algorithm").unwrap_or("none")
+			));
+		}
+
+		let (hash, padding) = match signed_info
+			.child(DSIG, "SignatureMethod")
+			.and_then(|m| m.attr("Algorithm"))
+		{
+			Some(RSA_SHA256) => (
+				sha256(&canonicalize(signed_info, None, &prefixes(c14n))),
+				Hash::SHA2_256,
+			),
+			Some(RSA_SHA1) => (
+				sha1(&canonicalize(signed_info, None, &prefixes(c14n))),
+				Hash::SHA1,
+			),
+			other => {
+				return Err(format!(
+					"unsupported signature method: {}",
+					other.unwrap_or("none")
+				))
+			}
+		};
+
+		let reference = signed_info
+			.child(DSIG, "Reference")
+			.ok_or("missing Reference")?;
+		if !reference.attr("URI").unwrap_or_default().is_empty() {
+			return Err("signature is not over the whole alert".into());
+		}
+
+		let mut inclusive = Vec::new();
+		let mut enveloped = false;
+		for transform in reference
+			.child(DSIG, "Transforms")
+			.map(|t| t.elements().filter(|e| e.is(DSIG, "Transform")).collect())
+			.unwrap_or_else(Vec::new)
+		{
+			match transform.attr("Algorithm") {
+				Some(ENVELOPED) => enveloped = true,
+				Some(EXC_C14N) => inclusive = prefixes(transform),
+				other => {
+					return Err(format!(
+						"unsupported transform: {}",
+						other.unwrap_or("none")
+					))
+				}
+			}
+		}
+
+		if !enveloped {
+			return Err("signature is not enveloped".into());
+		}
+
+		let document = canonicalize(&root, Some(signature), &inclusive);
+		trace!(%document, "canonicalized document");
+		let digest = match reference
+			.child(DSIG, "DigestMethod")
+			.and_then(|m| m.attr("Algorithm"))
+		{
+			Some(SHA256) => sha256(&document),
+			Some(SHA1) => sha1(&document),
+			other => {
+				return Err(format!(
+					"unsupported digest method: {}",
+					other.unwrap_or("none")
+				))
+			}
+		};
+
+		let expected = decode(
+			&reference
+				.child(DSIG, "DigestValue")
+				.ok_or("missing DigestValue")?
+				.text(),
+		)?;
+		if digest != expected {
+			return Err("digest doesn't match, the alert was changed after it was signed".into());
+		}
+
+		let value = decode(
+			&signature
+				.child(DSIG, "SignatureValue")
+				.ok_or("missing SignatureValue")?
+				.text(),
+		)?;
+
+		keys.into_iter()
+			.find(|(_, key)| {
+				key.verify(
+					PaddingScheme::new_pkcs1v15_sign(Some(padding)),
+					&hash,
+					&value,
+				)
+				.is_ok()
+			})
+			.map(|(name, _)| Some(name.clone()))
+			.ok_or_else(|| "not signed by a trusted key".into())
+	}
+}
+
+/// Public keys from PEM blocks of X.509 certificates or RSA public keys.
+fn parse_keys(pem: &str) -> Result<Vec<RsaPublicKey>> {
+	let mut keys = Vec::new();
+	for block in pem::parse_many(pem)? {
+		keys.push(match block.tag.as_str() {
+			"PUBLIC KEY" => RsaPublicKey::from_public_key_der(&block.contents)?,
+			"RSA PUBLIC KEY" => RsaPublicKey::from_pkcs1_der(&block.contents)?,
+			"CERTIFICATE" => RsaPublicKey::from_public_key_der(
+				certificate_key(&block.contents).ok_or_else(|| eyre!("invalid certificate"))?,
+			)?,
+			tag => {
+				warn!(%tag, "ignoring unsupported pem block");
+				continue;
+			}
+		});
+	}
+
+	Ok(keys)
+}
+
+fn sha1(text: &str) -> Vec<u8> {
+	Sha1::digest(text.as_bytes()).to_vec()
+}
+
+fn sha256(text: &str) -> Vec<u8> {
+	Sha256::digest(text.as_bytes()).to_vec()
+}
+
+fn decode(text: &str) -> Result<Vec<u8>, String> {
+	let text = text.split_whitespace().collect::<String>();
+	base64::decode(text).map_err(|err| err.to_string())
+}
+
+/// Prefixes from the `InclusiveNamespaces` of an exclusive canonicalisation method or transform.
+fn prefixes(method: &Element) -> Vec<String> {
+	method
+		.elements()
+		.find(|e| e.name.local_name == "InclusiveNamespaces")
+		.and_then(|e| e.attr("PrefixList"))
+		.map(|list| list.split_whitespace().map(String::from).collect())
+		.unwrap_or_default()
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+	Element(Element),
+	Text(String),
+	Instruction(String, Option<String>),
+}
+
+#[derive(Clone, Debug)]
+struct Element {
+	name: OwnedName,
+	attributes: Vec<OwnedAttribute>,
+
+	/// Namespaces in scope, including those declared on ancestors.
+	namespace: Namespace,
+	children: Vec<Node>,
+}
+
+impl Element {
+	fn is(&self, namespace: &str, local_name: &str) -> bool {
+		self.name.namespace.as_deref() == Some(namespace) && self.name.local_name == local_name
+	}
+
+	fn elements(&self) -> impl Iterator<Item = &Element> {
+		self.children.iter().filter_map(|n| match n {
+			Node::Element(e) => Some(e),
+			_ => None,
+		})
+	}
+
+	fn child(&self, namespace: &str, local_name: &str) -> Option<&Element> {
+		self.elements().find(|e| e.is(namespace, local_name))
+	}
+
+	/// Value of an unqualified attribute.
+	fn attr(&self, local_name: &str) -> Option<&str> {
+		self.attributes
+			.iter()
+			.find(|a| a.name.namespace.is_none() && a.name.local_name == local_name)
+			.map(|a| a.value.as_str())
+	}
+
+	fn text(&self) -> String {
+		self.children
+			.iter()
+			.filter_map(|n| match n {
+				Node::Text(t) => Some(t.as_str()),
+				_ => None,
+			})
+			.collect()
+	}
+}
+
+/// Parse a document into a tree, keeping everything canonicalisation needs (but not comments).
+fn parse(xml: &str) -> Result<Element, String> {
+	let reader = ParserConfig::new()
+		.trim_whitespace(false)
+		.whitespace_to_characters(true)
+		.cdata_to_characters(true)
+		.coalesce_characters(true)
+		.ignore_comments(true)
+		.create_reader(xml.as_bytes());
+
+	let mut stack: Vec<Element> = Vec::new();
+	let mut root = None;
+	for event in reader {
+		match event.map_err(|err| err.to_string())? {
+			XmlEvent::StartElement {
+				name,
+				attributes,
+				namespace,
+			} => stack.push(Element {
+				name,
+				attributes,
+				namespace,
+				children: Vec::new(),
+			}),
+			XmlEvent::EndElement { .. } => {
+				let element = stack.pop().ok_or("unbalanced document")?;
+				match stack.last_mut() {
+					Some(parent) => parent.children.push(Node::Element(element)),
+					None => root = Some(element),
+				}
+			}
+			// outside the root element, these don't count
+			XmlEvent::Characters(text) => {
+				if let Some(parent) = stack.last_mut() {
+					parent.children.push(Node::Text(text));
+				}
+			}
+			XmlEvent::ProcessingInstruction { name, data } => {
+				if let Some(parent) = stack.last_mut() {
+					parent.children.push(Node::Instruction(name, data));
+				}
+			}
+			_ => {}
+		}
+	}
+
+	root.ok_or_else(|| "empty document".into())
+}
+
+/// Exclusive XML canonicalisation (without comments) of an element, leaving out `exclude`.
+///
+/// https://www.w3.org/TR/xml-exc-c14n/
+fn canonicalize(element: &Element, exclude: Option<&Element>, inclusive: &[String]) -> String {
+	let mut out = String::new();
+	render(element, exclude, &BTreeMap::new(), inclusive, &mut out);
+	out
+}
+
+fn render(
+	element: &Element,
+	exclude: Option<&Element>,
+	rendered: &BTreeMap<String, String>,
+	inclusive: &[String],
+	out: &mut String,
+) {
+	// namespaces are only declared where they're visibly used, sorted by prefix (default first)
+	let mut used = BTreeMap::new();
+	used.insert(
+		element.name.prefix.clone().unwrap_or_default(),
+		element.name.namespace.clone().unwrap_or_default(),
+	);
+	for attr in &element.attributes {
+		if let (Some(prefix), Some(ns)) = (&attr.name.prefix, &attr.name.namespace) {
+			if prefix != "xml" {
+				used.insert(prefix.clone(), ns.clone());
+			}
+		}
+	}
+	for prefix in inclusive {
+		let prefix = if prefix == "#default" {
+			""
+		} else {
+			prefix.as_str()
+		};
+		if let Some(ns) = element.namespace.get(prefix) {
+			if prefix != "xml" && prefix != "xmlns" {
+				used.insert(prefix.into(), ns.into());
+			}
+		}
+	}
+
+	let mut rendered = rendered.clone();
+	let mut declarations = String::new();
+	for (prefix, ns) in used {
+		let declare = match rendered.get(&prefix) {
+			Some(current) => *current != ns,
+			None => !ns.is_empty(),
+		};
+		if !declare {
+			continue;
+		}
+
+		if prefix.is_empty() {
+			declarations.push_str(&format!(" xmlns=\"{}\"", escape_attr(&ns)));
+		} else {
+			declarations.push_str(&format!(" xmlns:{}=\"{}\"", prefix, escape_attr(&ns)));
+		}
+		rendered.insert(prefix, ns);
+	}
+
+	// attributes are sorted by namespace, then local name (unqualified ones first)
+	let mut attributes = element.attributes.iter().collect::<Vec<_>>();
+	attributes.sort_by(|a, b| {
+		(
+			a.name.namespace.as_deref().unwrap_or_default(),
+			&a.name.local_name,
+		)
+			.cmp(&(
+				b.name.namespace.as_deref().unwrap_or_default(),
+				&b.name.local_name,
+			))
+	});
+
+	let qname = qualified(&element.name);
+	out.push('<');
+	out.push_str(&qname);
+	out.push_str(&declarations);
+	for attr in attributes {
+		out.push_str(&format!(
+			" {}=\"{}\"",
+			qualified(&attr.name),
+			escape_attr(&attr.value)
+		));
+	}
+	out.push('>');
+
+	for child in &element.children {
+		match child {
+			Node::Element(e) if exclude.is_some_and(|x| std::ptr::eq(x, e)) => {}
+			Node::Element(e) => render(e, exclude, &rendered, inclusive, out),
+			Node::Text(text) => out.push_str(&escape_text(text)),
+			Node::Instruction(name, Some(data)) => out.push_str(&format!("<?{} {}?>", name, data)),
+			Node::Instruction(name, None) => out.push_str(&format!("<?{}?>", name)),
+		}
+	}
+
+	out.push_str("</");
+	out.push_str(&qname);
+	out.push('>');
+}
+
+fn qualified(name: &OwnedName) -> String {
+	match &name.prefix {
+		Some(prefix) if !prefix.is_empty() => format!("{}:{}", prefix, name.local_name),
+		_ => name.local_name.clone(),
+	}
+}
+
+fn escape_text(text: &str) -> String {
+	text.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('\r', "&#xD;")
+}
+
+fn escape_attr(value: &str) -> String {
+	value
+		.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('"', "&quot;")
+		.replace('\t', "&#x9;")
+		.replace('\n', "&#xA;")
+		.replace('\r', "&#xD;")
+}
+
+/// Find the SubjectPublicKeyInfo in a DER-encoded X.509 certificate.
+fn certificate_key(cert: &[u8]) -> Option<&[u8]> {
+	let (_, cert, _) = der(cert)?;
+	let (_, mut tbs, _) = der(cert)?;
+
+	// skip the version, if present, then the serial number, signature algorithm, issuer, validity, and subject
+	if tbs.first() == Some(&0xa0) {
+		tbs = der(tbs)?.2;
+	}
+	for _ in 0..5 {
+		tbs = der(tbs)?.2;
+	}
+
+	der(tbs).map(|(whole, _, _)| whole)
+}
+
+/// Split the first DER value off some input, as its whole encoding, its contents, and the rest.
+fn der(input: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+	let (_tag, rest) = input.split_first()?;
+	let (&first, rest) = rest.split_first()?;
+	let (len, rest) = if first & 0x80 == 0 {
+		(usize::from(first), rest)
+	} else {
+		let n = usize::from(first & 0x7f);
+		if n == 0 || n > 4 || rest.len() < n {
+			return None;
+		}
+		(
+			rest[..n]
+				.iter()
+				.fold(0, |len, &b| (len << 8) | usize::from(b)),
+			&rest[n..],
+		)
+	};
+
+	if rest.len() < len {
+		return None;
+	}
+
+	let header = input.len() - rest.len();
+	Some((&input[..header + len], &rest[..len], &rest[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SIGNED: &str = include_str!("../tests/fixtures/signed.xml");
+	const UNSIGNED: &str = include_str!("../tests/fixtures/alert.xml");
+	const CERTIFICATE: &str = include_str!("../tests/fixtures/trusted/alerts@metservice.com.pem");
+	const PUBLIC_KEY: &str = include_str!("../tests/fixtures/signer.pub");
+	const WRONG_KEY: &str = include_str!("../tests/fixtures/wrong.pub");
+
+	const SENDER: &str = "alerts@metservice.com";
+
+	fn verifier(name: &str, pem: &str) -> Verifier {
+		Verifier {
+			policy: SignaturePolicy::Flag,
+			keys: parse_keys(pem)
+				.unwrap()
+				.into_iter()
+				.map(|key| (name.to_string(), key))
+				.collect(),
+		}
+	}
+
+	fn verified() -> Verification {
+		Verification::Verified {
+			signer: SENDER.into(),
+		}
+	}
+
+	fn invalid(reason: &str) -> Verification {
+		Verification::Invalid {
+			reason: reason.into(),
+		}
+	}
+
+	#[test]
+	fn verifies_with_certificate() {
+		assert_eq!(verifier(SENDER, CERTIFICATE).verify(SIGNED), verified());
+	}
+
+	#[test]
+	fn verifies_with_public_key() {
+		assert_eq!(verifier(SENDER, PUBLIC_KEY).verify(SIGNED), verified());
+	}
+
+	#[test]
+	fn certificate_and_public_key_match() {
+		assert_eq!(
+			parse_keys(CERTIFICATE).unwrap(),
+			parse_keys(PUBLIC_KEY).unwrap()
+		);
+	}
+
+	#[tokio::test]
+	async fn loads_keys_named_after_sender() {
+		let path = Path::new(concat!(
+			env!("CARGO_MANIFEST_DIR"),
+			"/tests/fixtures/trusted"
+		));
+		let verifier = Verifier::load(SignaturePolicy::Drop, path)
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(verifier.verify(SIGNED), verified());
+
+		assert!(Verifier::load(SignaturePolicy::Ignore, path)
+			.await
+			.unwrap()
+			.is_none());
+	}
+
+	#[test]
+	fn rejects_tampered_body() {
+		let tampered = SIGNED.replace("100 to 150mm", "10 to 15mm");
+		assert_ne!(tampered, SIGNED);
+		assert_eq!(
+			verifier(SENDER, CERTIFICATE).verify(&tampered),
+			invalid("digest doesn't match, the alert was changed after it was signed")
+		);
+	}
+
+	#[test]
+	fn rejects_tampered_signed_info() {
+		// the digest still matches, but the signature over SignedInfo doesn't
+		let tampered = SIGNED.replace(r#"<Reference URI="">"#, r#"<Reference Id="r" URI="">"#);
+		assert_ne!(tampered, SIGNED);
+		assert_eq!(
+			verifier(SENDER, CERTIFICATE).verify(&tampered),
+			invalid("not signed by a trusted key")
+		);
+	}
+
+	#[test]
+	fn rejects_wrong_key() {
+		assert_eq!(
+			verifier(SENDER, WRONG_KEY).verify(SIGNED),
+			invalid("not signed by a trusted key")
+		);
+	}
+
+	#[test]
+	fn binds_keys_to_sender() {
+		// the right key, but trusted for someone else
+		assert_eq!(
+			verifier("alerts@example.com", CERTIFICATE).verify(SIGNED),
+			invalid("no trusted key for sender alerts@metservice.com")
+		);
+
+		// only the sender's own keys are tried
+		let mut mixed = verifier("alerts@example.com", CERTIFICATE);
+		mixed.keys.extend(verifier(SENDER, WRONG_KEY).keys);
+		assert_eq!(mixed.verify(SIGNED), invalid("not signed by a trusted key"));
+	}
+
+	#[test]
+	fn unsigned_alert() {
+		assert_eq!(
+			verifier(SENDER, CERTIFICATE).verify(UNSIGNED),
+			Verification::Unsigned
+		);
+	}
+
+	#[test]
+	fn applies_policy() {
+		let mut verifier = verifier(SENDER, CERTIFICATE);
+		assert_eq!(verifier.check("a", SIGNED).unwrap(), Some(verified()));
+		assert_eq!(
+			verifier.check("a", UNSIGNED).unwrap(),
+			Some(Verification::Unsigned)
+		);
+
+		verifier.policy = SignaturePolicy::Accept;
+		assert_eq!(verifier.check("a", UNSIGNED).unwrap(), None);
+
+		verifier.policy = SignaturePolicy::Drop;
+		assert!(verifier.check("a", UNSIGNED).is_err());
+		assert_eq!(verifier.check("a", SIGNED).unwrap(), Some(verified()));
+	}
+
+	#[test]
+	fn finds_certificate_key() {
+		let cert = pem::parse(CERTIFICATE).unwrap();
+		let key = pem::parse(PUBLIC_KEY).unwrap();
+		assert_eq!(certificate_key(&cert.contents), Some(&key.contents[..]));
+	}
+}
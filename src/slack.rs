@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+
+use color_eyre::eyre::{eyre, Result};
+use itertools::Itertools;
+use reqwest::{
+	multipart::{Form, Part},
+	Client,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{debug, trace};
+
+use crate::{
+	cache::Progress,
+	cap::Cap,
+	config::SlackSink,
+	output::{colour_hex, headline, split_long_message, when_where, Out},
+	retry,
+};
+
+/// Header blocks can only hold this many characters.
+const HEADER_LEN: usize = 150;
+
+/// Section blocks can only hold this many characters.
+const SECTION_LEN: usize = 3000;
+
+/// Post alerts as Block Kit messages, one attachment per alert, with the map uploaded as a file.
+///
+/// Incoming webhooks can't upload files, so the map is only sent when using a token and channel.
+/// A map uploaded by an earlier attempt isn't uploaded again.
+pub async fn send(sink: &SlackSink, caps: &[Cap], out: &Out, progress: &Progress) -> Result<()> {
+	let message = Message {
+		channel: sink.channel.clone(),
+		text: caps.iter().map(headline).unique().join("\n"),
+		attachments: caps.iter().map(attachment).collect(),
+	};
+
+	match (&sink.token, &sink.channel, &sink.webhook_url) {
+		(Some(token), Some(channel), _) => {
+			if let Some(ref image) = out.image {
+				let part = Out {
+					message: String::new(),
+					image: Some(image.clone()),
+				};
+				if progress.sent(0, &part)?.is_some() {
+					debug!("map was already uploaded to slack, skipping it");
+				} else {
+					let id = upload_image(&sink.api_url, token, channel, image).await?;
+					progress.record(0, &part, &id)?;
+				}
+			}
+
+			post_message(sink, token, &message).await
+		}
+		(_, _, Some(webhook_url)) => {
+			if out.image.is_some() {
+				debug!("slack webhooks can't upload files, skipping map");
+			}
+
+			post_webhook(webhook_url, &message).await
+		}
+		_ => Err(eyre!(
+			"slack needs either a webhook url, or a token and a channel"
+		)),
+	}
+}
+
+fn attachment(cap: &Cap) -> Attachment {
+	let mut header = headline(cap);
+	if header.chars().count() > HEADER_LEN {
+		header = header
+			.chars()
+			.take(HEADER_LEN - 1)
+			.chain(Some('…'))
+			.collect();
+	}
+
+	let mut blocks = vec![Block::Header {
+		text: Text::plain(header),
+	}];
+
+	let mut description = Out {
+		message: cap.info().description.clone(),
+		..Out::default()
+	};
+	loop {
+		let (first, rest) = split_long_message(description, SECTION_LEN, 280);
+		if !first.message.is_empty() {
+			blocks.push(Block::Section {
+				text: Text::markdown(first.message),
+			});
+		}
+
+		description = match rest {
+			Some(o) => o,
+			None => break,
+		};
+	}
+
+	blocks.push(Block::Context {
+		elements: vec![Text::markdown(when_where(cap))],
+	});
+
+	Attachment {
+		color: colour_hex(cap),
+		blocks,
+	}
+}
+
+async fn post_webhook(webhook_url: &str, message: &Message) -> Result<()> {
+	let client = Client::new();
+	let resp = retry::send("slack", || Ok(client.post(webhook_url).json(message))).await?;
+	trace!(?resp, "response from slack");
+	let status = resp.status();
+
+	let body = resp.text().await?;
+	trace!(%body, "response body");
+
+	if !status.is_success() {
+		Err(eyre!(
+			"failed to send message to slack: {}\n{}",
+			status,
+			body
+		))
+	} else {
+		Ok(())
+	}
+}
+
+async fn post_message(sink: &SlackSink, token: &str, message: &Message) -> Result<()> {
+	let client = Client::new();
+	api_call(|| {
+		Ok(client
+			.post(format!("{}/chat.postMessage", sink.api_url))
+			.bearer_auth(token)
+			.json(message))
+	})
+	.await
+	.map(drop)
+}
+
+/// Upload the map to the channel, returning the ID of the file.
+async fn upload_image(api_url: &str, token: &str, channel: &str, image: &[u8]) -> Result<String> {
+	let client = Client::new();
+	let body = api_call(|| {
+		let part = Part::bytes(image.to_vec())
+			.file_name("map.png")
+			.mime_str("image/png")?;
+		let form = Form::new()
+			.text("channels", channel.to_string())
+			.text("filename", "map.png")
+			.part("file", part);
+
+		Ok(client
+			.post(format!("{}/files.upload", api_url))
+			.bearer_auth(token)
+			.multipart(form))
+	})
+	.await?;
+
+	Ok(body
+		.get("file")
+		.and_then(|file| file.get("id"))
+		.and_then(Value::as_str)
+		.unwrap_or_default()
+		.into())
+}
+
+async fn api_call(
+	make: impl Fn() -> Result<reqwest::RequestBuilder>,
+) -> Result<HashMap<String, Value>> {
+	let resp = retry::send("slack", make).await?;
+	trace!(?resp, "response from slack");
+	let status = resp.status();
+
+	let body: HashMap<String, Value> = resp.json().await?;
+	trace!(?body, "response body");
+
+	if !status.is_success() || body.get("ok").and_then(Value::as_bool) != Some(true) {
+		Err(eyre!(
+			"failed to send message to slack: {}\n{:?}",
+			status,
+			body
+		))
+	} else {
+		Ok(body)
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Message {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	channel: Option<String>,
+
+	/// Fallback for notifications and clients that can't show blocks.
+	text: String,
+
+	attachments: Vec<Attachment>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Attachment {
+	color: &'static str,
+	blocks: Vec<Block>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Block {
+	Header { text: Text },
+	Section { text: Text },
+	Context { elements: Vec<Text> },
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Text {
+	r#type: &'static str,
+	text: String,
+}
+
+impl Text {
+	fn plain(text: impl Into<String>) -> Self {
+		Self {
+			r#type: "plain_text",
+			text: text.into(),
+		}
+	}
+
+	fn markdown(text: impl Into<String>) -> Self {
+		Self {
+			r#type: "mrkdwn",
+			text: text.into(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		cache::Cache,
+		cap::Reminder,
+		testing::{alert, http_server, http_server_replying},
+	};
+
+	#[tokio::test]
+	async fn webhook_posts_block_kit() {
+		let (url, requests) = http_server(1, "ok").await;
+		let sink = SlackSink {
+			webhook_url: Some(format!("{}/hook", url)),
+			token: None,
+			channel: None,
+			api_url: url,
+		};
+
+		let cap = alert();
+		let out = Out {
+			message: "ignored".into(),
+			image: Some(b"not uploaded".to_vec()),
+		};
+		let cache = Cache::temporary().unwrap();
+		let progress = cache.progress("default/slack", std::slice::from_ref(&cap));
+		send(&sink, std::slice::from_ref(&cap), &out, &progress)
+			.await
+			.unwrap();
+
+		let requests = requests.await.unwrap();
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].method, "POST");
+		assert_eq!(requests[0].path, "/hook");
+
+		let body = requests[0].json();
+		assert_eq!(body["text"], headline(&cap));
+		assert!(body.get("channel").is_none());
+
+		let attachment = &body["attachments"][0];
+		assert_eq!(attachment["color"], colour_hex(&cap));
+
+		let blocks = attachment["blocks"].as_array().unwrap();
+		assert_eq!(blocks[0]["type"], "header");
+		assert_eq!(blocks[0]["text"]["type"], "plain_text");
+		assert_eq!(blocks[0]["text"]["text"], headline(&cap));
+		assert_eq!(blocks[1]["type"], "section");
+		assert_eq!(blocks[1]["text"]["type"], "mrkdwn");
+		assert_eq!(blocks[1]["text"]["text"], cap.info().description);
+		assert_eq!(blocks[2]["type"], "context");
+		assert_eq!(blocks[2]["elements"][0]["text"], when_where(&cap));
+	}
+
+	#[tokio::test]
+	async fn token_uploads_map_then_posts() {
+		let (url, requests) = http_server(2, r#"{"ok":true}"#).await;
+		let sink = SlackSink {
+			webhook_url: None,
+			token: Some("xoxb-test".into()),
+			channel: Some("C0123".into()),
+			api_url: url,
+		};
+
+		let out = Out {
+			message: "ignored".into(),
+			image: Some(b"fake png".to_vec()),
+		};
+		let cache = Cache::temporary().unwrap();
+		let progress = cache.progress("default/slack", &[alert()]);
+		send(&sink, &[alert()], &out, &progress).await.unwrap();
+
+		let requests = requests.await.unwrap();
+		assert_eq!(requests.len(), 2);
+
+		let upload = &requests[0];
+		assert_eq!(upload.path, "/files.upload");
+		assert_eq!(upload.header("authorization"), Some("Bearer xoxb-test"));
+		assert!(upload
+			.header("content-type")
+			.unwrap()
+			.starts_with("multipart/form-data"));
+		let form = upload.text();
+		assert!(form.contains("name=\"channels\"\r\n\r\nC0123\r\n"));
+		assert!(form.contains("name=\"file\"; filename=\"map.png\""));
+		assert!(form.contains("Content-Type: image/png\r\n\r\nfake png\r\n"));
+
+		let post = &requests[1];
+		assert_eq!(post.path, "/chat.postMessage");
+		assert_eq!(post.header("authorization"), Some("Bearer xoxb-test"));
+		let body = post.json();
+		assert_eq!(body["channel"], "C0123");
+		assert_eq!(body["attachments"].as_array().unwrap().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn retry_skips_uploaded_map() {
+		let (url, requests) = http_server_replying(vec![
+			(200, r#"{"ok":true,"file":{"id":"F0123"}}"#),
+			(200, r#"{"ok":false,"error":"ratelimited"}"#),
+			(200, r#"{"ok":true}"#),
+		])
+		.await;
+		let sink = SlackSink {
+			webhook_url: None,
+			token: Some("xoxb-test".into()),
+			channel: Some("C0123".into()),
+			api_url: url,
+		};
+
+		let out = Out {
+			message: "ignored".into(),
+			image: Some(b"fake png".to_vec()),
+		};
+		let cache = Cache::temporary().unwrap();
+		let progress = cache.progress("default/slack", &[alert()]);
+		assert!(send(&sink, &[alert()], &out, &progress).await.is_err());
+		send(&sink, &[alert()], &out, &progress).await.unwrap();
+
+		let requests = requests.await.unwrap();
+		let paths: Vec<_> = requests.iter().map(|r| r.path.as_str()).collect();
+		assert_eq!(
+			paths,
+			["/files.upload", "/chat.postMessage", "/chat.postMessage"]
+		);
+	}
+
+	#[tokio::test]
+	async fn needs_webhook_or_token() {
+		let sink = SlackSink {
+			webhook_url: None,
+			token: Some("xoxb-test".into()),
+			channel: None,
+			api_url: "http://127.0.0.1:1".into(),
+		};
+
+		let cache = Cache::temporary().unwrap();
+		let progress = cache.progress("default/slack", &[alert()]);
+		assert!(send(&sink, &[alert()], &Out::default(), &progress)
+			.await
+			.is_err());
+	}
+
+	#[test]
+	fn reminder_is_short() {
+		let reminder = alert().reminder(Reminder::Onset);
+		let blocks = attachment(&reminder).blocks;
+		assert_eq!(blocks.len(), 2);
+		assert!(matches!(blocks[0], Block::Header { .. }));
+		assert!(matches!(blocks[1], Block::Context { .. }));
+	}
+}
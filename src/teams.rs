@@ -0,0 +1,311 @@
+use color_eyre::eyre::{eyre, Result};
+use reqwest::Client;
+use serde::Serialize;
+use tracing::{debug, trace, warn};
+
+use crate::{
+	cap::{Cap, Severity},
+	config::{Route, TeamsSink},
+	geodirs::Geodata,
+	output::{colour_code, headline, text_with_map, when_where, Out},
+	retry,
+};
+
+/// Teams rejects messages larger than about 28KB, so leave room for the text.
+const MAX_IMAGE_URI_LEN: usize = 20 * 1024;
+
+/// Smallest side a map is shrunk to so it fits in a card, below which it's left out instead.
+const MIN_IMAGE_SIDE: u32 = 128;
+
+/// Post alerts as an Adaptive Card, one container per alert, with the map embedded as a data URI.
+pub async fn send(
+	route: &Route,
+	sink: &TeamsSink,
+	geo: &Geodata,
+	caps: &[Cap],
+	out: &Out,
+) -> Result<()> {
+	let mut body = Vec::with_capacity(caps.len() + 1);
+
+	if let Some(uri) = image_uri(route, geo, caps, out).await? {
+		body.push(Element::Image {
+			url: uri,
+			alt_text: "Map of alert areas".into(),
+		});
+	}
+
+	body.extend(caps.iter().map(container));
+
+	let message = Message {
+		r#type: "message",
+		attachments: vec![Attachment {
+			content_type: "application/vnd.microsoft.card.adaptive",
+			content: Card {
+				schema: "http://adaptivecards.io/schemas/adaptive-card.json",
+				r#type: "AdaptiveCard",
+				version: "1.4",
+				body,
+			},
+		}],
+	};
+
+	let client = Client::new();
+	let resp = retry::send("teams", || {
+		Ok(client.post(&sink.webhook_url).json(&message))
+	})
+	.await?;
+	trace!(?resp, "response from teams");
+	let status = resp.status();
+
+	let body = resp.text().await?;
+	trace!(%body, "response body");
+
+	if !status.is_success() {
+		Err(eyre!(
+			"failed to send message to teams: {}\n{}",
+			status,
+			body
+		))
+	} else {
+		Ok(())
+	}
+}
+
+/// The map as a data URI, re-rendered smaller until it's small enough to embed.
+async fn image_uri(
+	route: &Route,
+	geo: &Geodata,
+	caps: &[Cap],
+	out: &Out,
+) -> Result<Option<String>> {
+	let mut image = match out.image {
+		Some(ref image) => image.clone(),
+		None => return Ok(None),
+	};
+
+	let (mut width, mut height) = (route.image_width, route.image_height);
+	loop {
+		let uri = format!("data:image/png;base64,{}", base64::encode(&image));
+		if uri.len() <= MAX_IMAGE_URI_LEN {
+			return Ok(Some(uri));
+		}
+
+		width = width * 3 / 4;
+		height = height * 3 / 4;
+		if width.min(height) < MIN_IMAGE_SIDE {
+			warn!(
+				bytes=%uri.len(),
+				"map is too large to embed in a teams card even when shrunk, skipping it"
+			);
+			return Ok(None);
+		}
+
+		debug!(bytes=%uri.len(), %width, %height, "map is too large to embed in a teams card, rendering it smaller");
+		let smaller = Route {
+			image_width: width,
+			image_height: height,
+			..route.clone()
+		};
+		image = match text_with_map(caps.iter().cloned().collect(), &smaller, geo)
+			.await?
+			.image
+		{
+			Some(image) => image,
+			None => return Ok(None),
+		};
+	}
+}
+
+fn container(cap: &Cap) -> Element {
+	let mut items = vec![
+		Element::TextBlock {
+			text: headline(cap),
+			weight: Some("bolder"),
+			size: Some("medium"),
+			wrap: true,
+		},
+		Element::TextBlock {
+			text: when_where(cap),
+			weight: None,
+			size: Some("small"),
+			wrap: true,
+		},
+	];
+
+	// reminders have no description
+	if !cap.info().description.is_empty() {
+		items.push(Element::TextBlock {
+			text: cap.info().description.clone(),
+			weight: None,
+			size: None,
+			wrap: true,
+		});
+	}
+
+	Element::Container {
+		style: style(cap),
+		bleed: true,
+		items,
+	}
+}
+
+/// Map the alert's `ColourCode`, or else its severity, to a container style.
+fn style(cap: &Cap) -> &'static str {
+	match colour_code(cap).map(str::to_lowercase).as_deref() {
+		Some("red") | Some("purple") => "attention",
+		Some("orange") | Some("yellow") => "warning",
+		Some("green") => "good",
+		Some("blue") => "accent",
+		_ => match cap.info().severity {
+			Severity::Extreme | Severity::Severe => "attention",
+			Severity::Moderate => "warning",
+			Severity::Minor => "accent",
+		},
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Message {
+	r#type: &'static str,
+	attachments: Vec<Attachment>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Attachment {
+	content_type: &'static str,
+	content: Card,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Card {
+	#[serde(rename = "$schema")]
+	schema: &'static str,
+	r#type: &'static str,
+	version: &'static str,
+	body: Vec<Element>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type")]
+enum Element {
+	Image {
+		url: String,
+		#[serde(rename = "altText")]
+		alt_text: String,
+	},
+	Container {
+		style: &'static str,
+		bleed: bool,
+		items: Vec<Element>,
+	},
+	TextBlock {
+		text: String,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		weight: Option<&'static str>,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		size: Option<&'static str>,
+		wrap: bool,
+	},
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::{alert, geodata, http_server};
+
+	fn sink(url: &str) -> TeamsSink {
+		TeamsSink {
+			webhook_url: format!("{}/workflow", url),
+		}
+	}
+
+	#[tokio::test]
+	async fn posts_adaptive_card() {
+		let (url, requests) = http_server(1, "1").await;
+		let cap = alert();
+		let out = Out {
+			message: "ignored".into(),
+			image: Some(b"fake png".to_vec()),
+		};
+		send(&Route::default(), &sink(&url), &geodata(), &[alert()], &out)
+			.await
+			.unwrap();
+
+		let requests = requests.await.unwrap();
+		assert_eq!(requests[0].path, "/workflow");
+
+		let body = requests[0].json();
+		assert_eq!(body["type"], "message");
+		let attachment = &body["attachments"][0];
+		assert_eq!(
+			attachment["contentType"],
+			"application/vnd.microsoft.card.adaptive"
+		);
+
+		let card = &attachment["content"];
+		assert_eq!(card["type"], "AdaptiveCard");
+		assert_eq!(card["version"], "1.4");
+
+		let elements = card["body"].as_array().unwrap();
+		assert_eq!(elements.len(), 2);
+		assert_eq!(elements[0]["type"], "Image");
+		assert_eq!(
+			elements[0]["url"],
+			format!("data:image/png;base64,{}", base64::encode("fake png"))
+		);
+
+		let container = &elements[1];
+		assert_eq!(container["type"], "Container");
+		assert_eq!(container["style"], "warning");
+		let items = container["items"].as_array().unwrap();
+		assert_eq!(items[0]["text"], headline(&cap));
+		assert_eq!(items[0]["weight"], "bolder");
+		assert_eq!(items[1]["text"], when_where(&cap));
+		assert!(items[1].get("weight").is_none());
+		assert_eq!(items[2]["text"], cap.info().description);
+	}
+
+	#[tokio::test]
+	async fn drops_map_too_large_to_shrink() {
+		let (url, requests) = http_server(1, "1").await;
+		// shrinking this by a quarter goes below the smallest side, so it's not re-rendered
+		let route = Route {
+			image_width: MIN_IMAGE_SIDE + 32,
+			image_height: MIN_IMAGE_SIDE + 32,
+			..Route::default()
+		};
+		let out = Out {
+			message: "ignored".into(),
+			image: Some(vec![0; MAX_IMAGE_URI_LEN]),
+		};
+		send(&route, &sink(&url), &geodata(), &[alert()], &out)
+			.await
+			.unwrap();
+
+		let body = requests.await.unwrap()[0].json();
+		let elements = body["attachments"][0]["content"]["body"]
+			.as_array()
+			.unwrap()
+			.clone();
+		assert_eq!(elements.len(), 1);
+		assert_eq!(elements[0]["type"], "Container");
+	}
+
+	#[test]
+	fn style_falls_back_to_severity() {
+		let mut cap = alert();
+		assert_eq!(style(&cap), "warning");
+
+		cap.infos[0].parameters.remove("ColourCode");
+		for (severity, expected) in [
+			(Severity::Extreme, "attention"),
+			(Severity::Severe, "attention"),
+			(Severity::Moderate, "warning"),
+			(Severity::Minor, "accent"),
+		] {
+			cap.infos[0].severity = severity;
+			assert_eq!(style(&cap), expected);
+		}
+	}
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+
+use color_eyre::eyre::{eyre, Result};
+use reqwest::{
+	multipart::{Form, Part},
+	Client, RequestBuilder,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::trace;
+
+use crate::{
+	cache::Progress,
+	config::TelegramSink,
+	output::{markup_to_telegram, split_long_message, Out},
+	retry,
+};
+
+/// Maximum length of a photo caption.
+const CAPTION_LEN: usize = 1024;
+
+/// Maximum length of a text message.
+const MESSAGE_LEN: usize = 4096;
+
+pub async fn send(sink: &TelegramSink, out: &Out, progress: &Progress) -> Result<()> {
+	let mut rest = Some(Out {
+		message: out.message.clone(),
+		image: None,
+	});
+
+	let mut part = 0;
+	if let Some(ref image) = out.image {
+		let (caption, remainder) = split_escaped(rest.take().unwrap(), CAPTION_LEN);
+		let sent = Out {
+			message: caption,
+			image: Some(image.clone()),
+		};
+		if progress.sent(part, &sent)?.is_none() {
+			let id = send_photo(sink, image, &sent.message).await?;
+			progress.record(part, &sent, &id)?;
+		}
+
+		part += 1;
+		rest = remainder;
+	}
+
+	while let Some(out) = rest {
+		let (text, remainder) = split_escaped(out, MESSAGE_LEN);
+		let sent = Out {
+			message: text,
+			image: None,
+		};
+		if progress.sent(part, &sent)?.is_none() {
+			let id = send_message(sink, &sent.message).await?;
+			progress.record(part, &sent, &id)?;
+		}
+
+		part += 1;
+		rest = remainder;
+	}
+
+	Ok(())
+}
+
+/// Split off the start of a message such that it fits in `max_len` once escaped.
+fn split_escaped(out: Out, max_len: usize) -> (String, Option<Out>) {
+	let mut max = max_len;
+	loop {
+		let (first, rest) = split_long_message(out.clone(), max, 280);
+		let escaped = markup_to_telegram(&first.message);
+		let len = escaped.chars().count();
+		if len <= max_len || max <= 1 {
+			return (escaped, rest);
+		}
+
+		trace!(%max, %len, "message too long once escaped, splitting shorter");
+		max = max.saturating_sub(len - max_len).max(1);
+	}
+}
+
+async fn send_photo(sink: &TelegramSink, image: &[u8], caption: &str) -> Result<String> {
+	let client = Client::new();
+	request(|| {
+		let part = Part::bytes(image.to_vec())
+			.file_name("map.png")
+			.mime_str("image/png")?;
+		let mut form = Form::new()
+			.text("chat_id", sink.chat_id.clone())
+			.part("photo", part);
+		if !caption.is_empty() {
+			form = form
+				.text("caption", caption.to_string())
+				.text("parse_mode", "MarkdownV2");
+		}
+
+		Ok(client.post(method_url(sink, "sendPhoto")).multipart(form))
+	})
+	.await
+}
+
+async fn send_message(sink: &TelegramSink, text: &str) -> Result<String> {
+	let message = Message {
+		chat_id: sink.chat_id.clone(),
+		text: text.into(),
+		parse_mode: "MarkdownV2",
+	};
+
+	let client = Client::new();
+	request(|| Ok(client.post(method_url(sink, "sendMessage")).json(&message))).await
+}
+
+fn method_url(sink: &TelegramSink, method: &str) -> String {
+	format!("{}/bot{}/{}", sink.api_url, sink.token, method)
+}
+
+/// Make a Bot API request, returning the ID of the message it sent.
+async fn request(make: impl Fn() -> Result<RequestBuilder>) -> Result<String> {
+	// not tracing the request itself, as the token is in the url
+	let resp = retry::send("telegram", make).await?;
+	let status = resp.status();
+	trace!(%status, "response from telegram");
+
+	let body: HashMap<String, Value> = resp.json().await?;
+	trace!(?body, "response body");
+
+	if !status.is_success() || body.get("ok").and_then(Value::as_bool) != Some(true) {
+		Err(eyre!(
+			"failed to send message to telegram: {}\n{:?}",
+			status,
+			body
+		))
+	} else {
+		Ok(body
+			.get("result")
+			.and_then(|result| result.get("message_id"))
+			.map(Value::to_string)
+			.unwrap_or_default())
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Message {
+	chat_id: String,
+	text: String,
+	parse_mode: &'static str,
+}
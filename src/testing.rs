@@ -0,0 +1,218 @@
+//! Fixtures and fake servers for the sink tests.
+
+use std::collections::HashMap;
+
+use geo::MultiPolygon;
+use reqwest::StatusCode;
+use tokio::{
+	io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+	net::TcpListener,
+	task::JoinHandle,
+};
+
+use crate::{cap::Cap, geodirs::Geodata};
+
+/// A severe, orange heavy rain warning with one area.
+pub fn alert() -> Cap {
+	let mut cap: Cap = serde_xml_rs::from_str(include_str!("../tests/fixtures/alert.xml"))
+		.expect("fixture is a valid CAP");
+	cap.item = cap.guid.clone();
+	cap
+}
+
+/// Geodata without any boundaries, outlines, or geocodes, for sinks that don't draw maps themselves.
+pub fn geodata() -> Geodata {
+	Geodata {
+		boundaries: MultiPolygon(Vec::new()),
+		named: Vec::new(),
+		boundaries_area: 0.0,
+		hull: MultiPolygon(Vec::new()),
+		outlines: MultiPolygon(Vec::new()),
+		geocodes: HashMap::new(),
+	}
+}
+
+/// A request received by the fake HTTP server.
+#[derive(Clone, Debug)]
+pub struct Request {
+	pub method: String,
+	pub path: String,
+
+	/// Header names are lowercased.
+	pub headers: HashMap<String, String>,
+	pub body: Vec<u8>,
+}
+
+impl Request {
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers.get(&name.to_lowercase()).map(String::as_str)
+	}
+
+	pub fn text(&self) -> String {
+		String::from_utf8_lossy(&self.body).into_owned()
+	}
+
+	pub fn json(&self) -> serde_json::Value {
+		serde_json::from_slice(&self.body).expect("request body is json")
+	}
+}
+
+/// Serve `count` HTTP requests on a local port, answering each with `200 OK` and `response`.
+///
+/// Returns the base URL of the server, and a handle resolving to the requests once all are in.
+pub async fn http_server(
+	count: usize,
+	response: &'static str,
+) -> (String, JoinHandle<Vec<Request>>) {
+	http_server_replying(vec![(200, response); count]).await
+}
+
+/// Serve one HTTP request per reply on a local port, answering them in order with each status and body.
+pub async fn http_server_replying(
+	replies: Vec<(u16, &'static str)>,
+) -> (String, JoinHandle<Vec<Request>>) {
+	let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let url = format!("http://{}", listener.local_addr().unwrap());
+
+	let handle = tokio::spawn(async move {
+		let mut requests = Vec::with_capacity(replies.len());
+		for (status, response) in replies {
+			let (stream, _) = listener.accept().await.unwrap();
+			let mut stream = BufReader::new(stream);
+
+			let mut line = String::new();
+			stream.read_line(&mut line).await.unwrap();
+			let mut start = line.split_whitespace();
+			let method = start.next().unwrap_or_default().to_string();
+			let path = start.next().unwrap_or_default().to_string();
+
+			let mut headers = HashMap::new();
+			loop {
+				line.clear();
+				stream.read_line(&mut line).await.unwrap();
+				let header = line.trim_end();
+				if header.is_empty() {
+					break;
+				}
+
+				if let Some((name, value)) = header.split_once(':') {
+					headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+				}
+			}
+
+			let mut body = Vec::new();
+			if let Some(len) = headers.get("content-length") {
+				body.resize(len.parse().unwrap(), 0);
+				stream.read_exact(&mut body).await.unwrap();
+			} else if headers.get("transfer-encoding").map(String::as_str) == Some("chunked") {
+				loop {
+					line.clear();
+					stream.read_line(&mut line).await.unwrap();
+					let len = usize::from_str_radix(line.trim(), 16).unwrap();
+					let mut chunk = vec![0; len + 2];
+					stream.read_exact(&mut chunk).await.unwrap();
+					if len == 0 {
+						break;
+					}
+
+					body.extend_from_slice(&chunk[..len]);
+				}
+			}
+
+			let reply = format!(
+				"HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+				status,
+				StatusCode::from_u16(status)
+					.ok()
+					.and_then(|s| s.canonical_reason())
+					.unwrap_or("Unknown"),
+				response.len(),
+				response
+			);
+			stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+			stream.get_mut().shutdown().await.unwrap();
+
+			requests.push(Request {
+				method,
+				path,
+				headers,
+				body,
+			});
+		}
+
+		requests
+	});
+
+	(url, handle)
+}
+
+/// Accept one SMTP session on a local port, accepting every command.
+///
+/// Returns the port, and a handle resolving to the commands and the message data, in order.
+pub async fn smtp_server() -> (u16, JoinHandle<Vec<String>>) {
+	let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let port = listener.local_addr().unwrap().port();
+
+	let handle = tokio::spawn(async move {
+		let (stream, _) = listener.accept().await.unwrap();
+		let mut stream = BufReader::new(stream);
+		let mut session = Vec::new();
+
+		stream
+			.get_mut()
+			.write_all(b"220 localhost ESMTP\r\n")
+			.await
+			.unwrap();
+
+		let mut line = String::new();
+		loop {
+			line.clear();
+			if stream.read_line(&mut line).await.unwrap() == 0 {
+				break;
+			}
+
+			let command = line.trim_end().to_string();
+			let verb = command
+				.split_whitespace()
+				.next()
+				.unwrap_or_default()
+				.to_uppercase();
+			session.push(command);
+
+			let reply: &[u8] = match verb.as_str() {
+				"DATA" => {
+					stream
+						.get_mut()
+						.write_all(b"354 go ahead\r\n")
+						.await
+						.unwrap();
+
+					let mut data = String::new();
+					loop {
+						line.clear();
+						stream.read_line(&mut line).await.unwrap();
+						if line == ".\r\n" {
+							break;
+						}
+
+						data.push_str(&line);
+					}
+
+					session.push(data);
+					b"250 queued\r\n"
+				}
+				"QUIT" => {
+					stream.get_mut().write_all(b"221 bye\r\n").await.unwrap();
+					break;
+				}
+				_ => b"250 ok\r\n",
+			};
+
+			stream.get_mut().write_all(reply).await.unwrap();
+		}
+
+		session
+	});
+
+	(port, handle)
+}
@@ -0,0 +1,278 @@
+use std::{
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::Result;
+use futures::future::join_all;
+use itertools::Itertools;
+use tokio::{sync::watch, time::sleep};
+use tracing::{debug, error, info};
+
+use crate::{
+	cache::{Cache, ItemState},
+	cap::{Cap, MsgType, Reminder},
+	config::{Route, WebhookSink},
+	feed,
+	geodirs::Geodata,
+	process, remind, report_failures,
+	signature::Verifier,
+	tidy, Args,
+};
+
+/// How often to check for reminders that are due.
+const REMINDER_CHECK: Duration = Duration::from_secs(30);
+
+/// What the poll loops of every feed share.
+struct Watcher<'a> {
+	args: &'a Args,
+	cache: &'a Cache,
+	routes: &'a [(Route, Geodata)],
+	admin: Option<&'a WebhookSink>,
+	verifier: Option<Arc<Verifier>>,
+
+	/// Reminders to post about alerts once their onset or expiry comes, soonest or not.
+	reminders: Mutex<Vec<Scheduled>>,
+}
+
+/// A reminder about a posted alert, to send once its time comes.
+///
+/// These are only kept in memory, so reminders due while capchat isn't running are not sent.
+struct Scheduled {
+	at: DateTime<Utc>,
+	route: String,
+
+	/// Identifier of the original alert, so updates and cancellations replace its reminders.
+	original: String,
+	cap: Cap,
+}
+
+pub async fn run(
+	args: &Args,
+	cache: &Cache,
+	routes: &[(Route, Geodata)],
+	admin: Option<&WebhookSink>,
+	verifier: Option<Arc<Verifier>>,
+) -> Result<()> {
+	let (stop_tx, stop_rx) = watch::channel(false);
+
+	tokio::spawn(async move {
+		match shutdown_signal().await {
+			Ok(()) => info!("shutting down after in-flight work completes"),
+			Err(err) => error!("failed to listen for shutdown signals: {}", err),
+		}
+		stop_tx.send(true).ok();
+	});
+
+	let urls = routes
+		.iter()
+		.flat_map(|(r, _)| r.feeds.iter())
+		.unique()
+		.collect::<Vec<_>>();
+
+	let watcher = Watcher {
+		args,
+		cache,
+		routes,
+		admin,
+		verifier,
+		reminders: Mutex::new(Vec::new()),
+	};
+
+	info!(feeds=%urls.len(), routes=%routes.len(), "watching feeds");
+	futures::join!(
+		join_all(
+			urls.into_iter()
+				.map(|url| poll_feed(&watcher, url, stop_rx.clone()))
+		),
+		tidy_loop(args, cache, routes, stop_rx.clone()),
+		reminder_loop(&watcher, stop_rx.clone()),
+	);
+
+	Ok(())
+}
+
+async fn tidy_loop(
+	args: &Args,
+	cache: &Cache,
+	routes: &[(Route, Geodata)],
+	mut stop: watch::Receiver<bool>,
+) {
+	let every = Duration::from_secs(args.min_interval);
+
+	while !*stop.borrow() {
+		for (route, _) in routes {
+			if let Err(err) = tidy(route, cache).await {
+				error!(route=%route.name, "failed to tidy up posted messages: {}", err);
+			}
+		}
+
+		tokio::select! {
+			_ = sleep(every) => {}
+			_ = stop.changed() => {}
+		}
+	}
+}
+
+async fn poll_feed(watcher: &Watcher<'_>, url: &str, mut stop: watch::Receiver<bool>) {
+	let Watcher {
+		args,
+		cache,
+		routes,
+		admin,
+		..
+	} = *watcher;
+	let default = Duration::from_secs(args.interval);
+	let min = Duration::from_secs(args.min_interval);
+
+	while !*stop.borrow() {
+		let fetched = feed::fetch_feed(
+			cache.clone(),
+			url.to_string(),
+			args.retry_failed,
+			watcher.verifier.clone(),
+		)
+		.await;
+		let ttl = match fetched {
+			Ok(feed) => {
+				debug!(%url, "fetched {} new caps", feed.caps.len());
+				report_failures(admin, &feed.failed).await;
+				if !feed.caps.is_empty() {
+					let mut delivered = true;
+					for (route, geo) in routes
+						.iter()
+						.filter(|(r, _)| r.feeds.iter().any(|f| f == url))
+					{
+						match process(route, cache, geo, feed.caps.iter().cloned().collect()).await
+						{
+							Ok(posted) if route.reminders => schedule(watcher, route, &posted),
+							Ok(_) => {}
+							Err(err) => {
+								error!(%url, route=%route.name, "failed to process caps: {}", err);
+								delivered = false;
+							}
+						}
+					}
+
+					if delivered {
+						for cap in &feed.caps {
+							if let Err(err) = cache.set_item_state(&cap.item, ItemState::Delivered)
+							{
+								error!(%url, guid=%cap.guid, "failed to mark cap as delivered: {}", err);
+							}
+						}
+					}
+				}
+				feed.ttl
+			}
+			Err(err) => {
+				error!(%url, "failed to fetch feed: {}", err);
+				None
+			}
+		};
+
+		let wait = ttl.map_or(default, |ttl| ttl.max(min));
+		debug!(%url, ?wait, "waiting until next poll");
+		tokio::select! {
+			_ = sleep(wait) => {}
+			_ = stop.changed() => {}
+		}
+	}
+
+	debug!(%url, "stopped watching feed");
+}
+
+/// Schedule reminders for the onset and expiry of posted alerts, replacing those of alerts they update.
+fn schedule(watcher: &Watcher<'_>, route: &Route, caps: &[Cap]) {
+	let now = Utc::now();
+	let mut reminders = watcher
+		.reminders
+		.lock()
+		.expect("reminder schedule poisoned");
+
+	for cap in caps {
+		let original = cap.supersedes.first().unwrap_or(&cap.guid).clone();
+		reminders.retain(|r| r.route != route.name || r.original != original);
+		if cap.msg_type == MsgType::Cancel {
+			continue;
+		}
+
+		let times = [
+			(Reminder::Onset, cap.info().onset),
+			(Reminder::Expiry, cap.info().expires),
+		];
+		for (kind, at) in times.iter().copied() {
+			let at = match at {
+				Some(at) if at > now => at,
+				_ => continue,
+			};
+
+			debug!(route=%route.name, guid=%cap.guid, ?kind, %at, "scheduling reminder");
+			reminders.push(Scheduled {
+				at,
+				route: route.name.clone(),
+				original: original.clone(),
+				cap: cap.reminder(kind),
+			});
+		}
+	}
+}
+
+async fn reminder_loop(watcher: &Watcher<'_>, mut stop: watch::Receiver<bool>) {
+	while !*stop.borrow() {
+		let now = Utc::now();
+		let due = {
+			let mut reminders = watcher
+				.reminders
+				.lock()
+				.expect("reminder schedule poisoned");
+			let (due, later): (Vec<_>, Vec<_>) = reminders.drain(..).partition(|r| r.at <= now);
+			*reminders = later;
+			due
+		};
+
+		for reminder in due {
+			let (route, geo) = match watcher
+				.routes
+				.iter()
+				.find(|(r, _)| r.name == reminder.route)
+			{
+				Some(found) => found,
+				None => continue,
+			};
+
+			if let Err(err) = remind(route, watcher.cache, geo, &reminder.cap).await {
+				error!(route=%route.name, guid=%reminder.cap.guid, "failed to send reminder: {}", err);
+			}
+		}
+
+		tokio::select! {
+			_ = sleep(REMINDER_CHECK) => {}
+			_ = stop.changed() => {}
+		}
+	}
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<()> {
+	use tokio::signal::unix::{signal, SignalKind};
+
+	let mut term = signal(SignalKind::terminate())?;
+	tokio::select! {
+		_ = term.recv() => info!("received SIGTERM"),
+		res = tokio::signal::ctrl_c() => {
+			res?;
+			info!("received interrupt");
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<()> {
+	tokio::signal::ctrl_c().await?;
+	info!("received interrupt");
+	Ok(())
+}
@@ -0,0 +1,274 @@
+use std::str::FromStr;
+
+use color_eyre::eyre::{eyre, Result};
+use handlebars::{handlebars_helper, Handlebars};
+use hmac::{Hmac, Mac, NewMac};
+use reqwest::{
+	multipart::{Form, Part},
+	Client,
+};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::fs::read_to_string;
+use tracing::{debug, trace};
+
+use crate::{
+	cap::Cap,
+	config::{Route, WebhookSink},
+	output::Out,
+	retry,
+};
+
+/// How to send the map image to a webhook.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageMode {
+	/// Don't send the image.
+	None,
+
+	/// Provide the image to the template as a base64 string.
+	Base64,
+
+	/// Send the payload and the image as separate multipart parts.
+	Multipart,
+}
+
+impl FromStr for ImageMode {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_lowercase().as_str() {
+			"none" => Ok(Self::None),
+			"base64" => Ok(Self::Base64),
+			"multipart" => Ok(Self::Multipart),
+			_ => Err(format!("unknown image mode: {}", s)),
+		}
+	}
+}
+
+/// What templates have access to.
+#[derive(Clone, Debug, Serialize)]
+struct Context<'a> {
+	route: &'a str,
+	caps: &'a [Cap],
+	message: &'a str,
+	image: Option<String>,
+}
+
+/// Post alerts to an arbitrary webhook, with the body rendered from a Handlebars template.
+///
+/// Without a template, the body is the template context as JSON.
+pub async fn send(route: &Route, sink: &WebhookSink, caps: &[Cap], out: &Out) -> Result<()> {
+	let context = Context {
+		route: &route.name,
+		caps,
+		message: &out.message,
+		image: match (&out.image, sink.image) {
+			(Some(image), ImageMode::Base64) => Some(base64::encode(image)),
+			_ => None,
+		},
+	};
+
+	let payload = if let Some(path) = &sink.template {
+		debug!(?path, "rendering webhook template");
+		render(&read_to_string(path).await?, &context)?
+	} else {
+		serde_json::to_string(&context)?
+	};
+	trace!(%payload, "webhook payload");
+
+	let signature = match &sink.secret {
+		Some(secret) => Some(sign(secret, &payload)?),
+		None => None,
+	};
+
+	let client = Client::new();
+	let resp = retry::send("webhook", || {
+		let mut req = client.post(&sink.url);
+
+		// content type is set below, as it applies to the payload part when sending multipart
+		for (name, value) in sink
+			.headers
+			.iter()
+			.filter(|(h, _)| !h.eq_ignore_ascii_case("content-type"))
+		{
+			req = req.header(name.as_str(), value.as_str());
+		}
+
+		if let Some(signature) = &signature {
+			req = req.header(
+				sink.signature_header.as_str(),
+				format!("sha256={}", signature),
+			);
+		}
+
+		let req = match (&out.image, sink.image) {
+			(Some(image), ImageMode::Multipart) => {
+				let form = Form::new()
+					.part(
+						"payload",
+						Part::text(payload.clone()).mime_str(content_type(sink))?,
+					)
+					.part(
+						"image",
+						Part::bytes(image.clone())
+							.file_name("map.png")
+							.mime_str("image/png")?,
+					);
+				req.multipart(form)
+			}
+			_ => req
+				.header("Content-Type", content_type(sink))
+				.body(payload.clone()),
+		};
+
+		trace!(?req, "sending request");
+		Ok(req)
+	})
+	.await?;
+	trace!(?resp, "response from webhook");
+	let status = resp.status();
+
+	let body = resp.text().await?;
+	trace!(%body, "response body");
+
+	if !status.is_success() {
+		Err(eyre!(
+			"failed to send message to webhook: {}\n{}",
+			status,
+			body
+		))
+	} else {
+		Ok(())
+	}
+}
+
+/// Hex-encoded HMAC-SHA256 of the payload.
+fn sign(secret: &str, payload: &str) -> Result<String> {
+	let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+		.map_err(|err| eyre!("invalid webhook secret: {}", err))?;
+	mac.update(payload.as_bytes());
+	Ok(mac
+		.finalize()
+		.into_bytes()
+		.iter()
+		.map(|b| format!("{:02x}", b))
+		.collect())
+}
+
+fn content_type(sink: &WebhookSink) -> &str {
+	sink.headers
+		.iter()
+		.find(|(h, _)| h.eq_ignore_ascii_case("content-type"))
+		.map_or("application/json", |(_, v)| v.as_str())
+}
+
+/// Render a template, escaping values for use inside JSON strings.
+///
+/// Use `{{{json value}}}` to insert a value as raw JSON instead.
+fn render(template: &str, context: &Context) -> Result<String> {
+	handlebars_helper!(json: |v: Json| v.to_string());
+
+	let mut hb = Handlebars::new();
+	hb.set_strict_mode(true);
+	hb.register_helper("json", Box::new(json));
+	hb.register_escape_fn(|s| {
+		let quoted = serde_json::Value::from(s).to_string();
+		quoted[1..quoted.len() - 1].to_string()
+	});
+
+	Ok(hb.render_template(template, context)?)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::*;
+	use crate::testing::{alert, http_server};
+
+	fn context<'a>(caps: &'a [Cap], message: &'a str) -> Context<'a> {
+		Context {
+			route: "default",
+			caps,
+			message,
+			image: None,
+		}
+	}
+
+	#[test]
+	fn sign_matches_rfc_4231() {
+		assert_eq!(
+			sign("Jefe", "what do ya want for nothing?").unwrap(),
+			"5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+		);
+	}
+
+	#[tokio::test]
+	async fn signs_the_body_it_sends() {
+		let (url, requests) = http_server(1, "ok").await;
+		let sink = WebhookSink {
+			url: format!("{}/hook", url),
+			template: None,
+			headers: HashMap::from([("X-Token".to_string(), "abc".to_string())]),
+			secret: Some("s3cret".into()),
+			signature_header: "X-Hub-Signature-256".into(),
+			image: ImageMode::None,
+		};
+
+		let out = Out {
+			message: "Heavy rain".into(),
+			image: Some(b"not sent".to_vec()),
+		};
+		send(&Route::default(), &sink, &[alert()], &out)
+			.await
+			.unwrap();
+
+		let requests = requests.await.unwrap();
+		let request = &requests[0];
+		assert_eq!(request.path, "/hook");
+		assert_eq!(request.header("x-token"), Some("abc"));
+		assert_eq!(request.header("content-type"), Some("application/json"));
+		assert_eq!(
+			request.header("x-hub-signature-256"),
+			Some(format!("sha256={}", sign("s3cret", &request.text()).unwrap()).as_str())
+		);
+
+		let body = request.json();
+		assert_eq!(body["message"], "Heavy rain");
+		assert_eq!(body["caps"].as_array().unwrap().len(), 1);
+		assert!(body["image"].is_null());
+	}
+
+	#[test]
+	fn render_escapes_values_for_json() {
+		let caps = [alert()];
+		let message = "Periods of \"heavy\" rain\n\tand wind \\ gusts";
+		let rendered = render(
+			r#"{"route": "{{route}}", "text": "{{message}}", "sender": "{{caps.0.sender}}"}"#,
+			&context(&caps, message),
+		)
+		.unwrap();
+
+		let body: serde_json::Value = serde_json::from_str(&rendered).unwrap();
+		assert_eq!(body["route"], "default");
+		assert_eq!(body["text"], message);
+		assert_eq!(body["sender"], "alerts@metservice.com");
+	}
+
+	#[test]
+	fn render_inserts_raw_json() {
+		let caps = [alert()];
+		let rendered = render(
+			r#"{"message": {{{json message}}}}"#,
+			&context(&caps, "a \"quoted\" message"),
+		)
+		.unwrap();
+		assert_eq!(rendered, r#"{"message": "a \"quoted\" message"}"#);
+	}
+
+	#[test]
+	fn render_rejects_unknown_values() {
+		let caps = [alert()];
+		assert!(render("{{headline}}", &context(&caps, "")).is_err());
+	}
+}